@@ -7,7 +7,7 @@ use utils::{
 };
 
 fn main() {
-    let mut benchmark_results: kv_store::KVStore = kv_store::KVStore::new("./previous_benchmarks");
+    let mut benchmark_results: kv_store::KVStore = kv_store::KVStore::open("./previous_benchmarks", kv_store::Options::default()).unwrap();
 
     benchmark_kv_store(
         &mut benchmark_results,
@@ -16,7 +16,7 @@ fn main() {
         |_| {},
         |kv| {
             for _ in 0..100_000 {
-                kv.set(random_bytes(), random_bytes());
+                kv.set(random_bytes(), random_bytes()).unwrap();
             }
         },
     );
@@ -29,7 +29,7 @@ fn main() {
         |_| {},
         |kv| {
             for _ in 0..10_000 {
-                black_box(kv.get(&random_bytes()));
+                black_box(kv.get(&random_bytes()).unwrap());
             }
         },
     );
@@ -40,12 +40,12 @@ fn main() {
         32,
         |kv| {
             for _ in 0..100 {
-                kv.set(random_bytes(), random_bytes());
+                kv.set(random_bytes(), random_bytes()).unwrap();
             }
         },
         |kv| {
             for _ in 0..100 {
-                black_box(kv.get(&random_bytes()));
+                black_box(kv.get(&random_bytes()).unwrap());
             }
         },
     );
@@ -56,12 +56,12 @@ fn main() {
         32,
         |kv| {
             for _ in 0..1000 {
-                kv.set(random_bytes(), random_bytes());
+                kv.set(random_bytes(), random_bytes()).unwrap();
             }
         },
         |kv| {
             for _ in 0..100 {
-                black_box(kv.get(&random_bytes()));
+                black_box(kv.get(&random_bytes()).unwrap());
             }
         },
     );
@@ -72,12 +72,12 @@ fn main() {
         8,
         |kv| {
             for _ in 0..10_000 {
-                kv.set(random_bytes(), random_bytes());
+                kv.set(random_bytes(), random_bytes()).unwrap();
             }
         },
         |kv| {
             for _ in 0..100 {
-                black_box(kv.get(&random_bytes()));
+                black_box(kv.get(&random_bytes()).unwrap());
             }
         },
     );
@@ -91,14 +91,14 @@ fn main() {
             |kv| {
                 let add_entries = entries.clone();
                 for entry in add_entries {
-                    kv.set(entry.0, entry.1);
+                    kv.set(entry.0, entry.1).unwrap();
                 }
             },
             |kv| {
                 let mut get_entries = entries.clone();
                 shuffle_vec(&mut get_entries);
                 for entry in get_entries {
-                    black_box(kv.get(&entry.0));
+                    black_box(kv.get(&entry.0).unwrap());
                 }
             },
         );
@@ -116,14 +116,14 @@ fn main() {
             |kv| {
                 let add_entries = all_entries.clone();
                 for entry in add_entries {
-                    kv.set(entry.0, entry.1);
+                    kv.set(entry.0, entry.1).unwrap();
                 }
             },
             |kv| {
                 let mut get_entries = some_entries.clone();
                 shuffle_vec(&mut get_entries);
                 for entry in get_entries {
-                    black_box(kv.get(&entry.0));
+                    black_box(kv.get(&entry.0).unwrap());
                 }
             },
         );
@@ -141,14 +141,14 @@ fn main() {
             |kv| {
                 let add_entries = all_entries.clone();
                 for entry in add_entries {
-                    kv.set(entry.0, entry.1);
+                    kv.set(entry.0, entry.1).unwrap();
                 }
             },
             |kv| {
                 let mut get_entries = some_entries.clone();
                 shuffle_vec(&mut get_entries);
                 for entry in get_entries {
-                    black_box(kv.get(&entry.0));
+                    black_box(kv.get(&entry.0).unwrap());
                 }
             },
         );
@@ -166,14 +166,14 @@ fn main() {
             |kv| {
                 let add_entries = all_entries.clone();
                 for entry in add_entries {
-                    kv.set(entry.0, entry.1);
+                    kv.set(entry.0, entry.1).unwrap();
                 }
             },
             |kv| {
                 let mut get_entries = some_entries.clone();
                 shuffle_vec(&mut get_entries);
                 for entry in get_entries {
-                    black_box(kv.get(&entry.0));
+                    black_box(kv.get(&entry.0).unwrap());
                 }
             },
         );
@@ -181,7 +181,7 @@ fn main() {
 
     {
         let initial = random_entries(100_000);
-        let mut get_entries = (&initial[..6_00]).to_vec();
+        let mut get_entries = initial[..6_00].to_vec();
         let set_entries = random_entries(3_00);
         get_entries.append(&mut random_entries(1_00));
 
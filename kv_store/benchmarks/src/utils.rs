@@ -27,7 +27,7 @@ pub fn random_entries(n: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
     (0..n).map(|_| (random_bytes(), random_bytes())).collect()
 }
 
-pub fn shuffle_vec<T>(vec: &mut Vec<T>) {
+pub fn shuffle_vec<T>(vec: &mut [T]) {
     vec.shuffle(&mut thread_rng());
 }
 
@@ -36,7 +36,7 @@ fn print_benchmark_result(
     name: &str,
     duration: Duration,
 ) {
-    match benchmark_results.get(&serialize_string(name)) {
+    match benchmark_results.get(&serialize_string(name)).unwrap() {
         Some(previous_duration_micros) => {
             let previous_duration = deserialize_duration(&previous_duration_micros);
             println!(
@@ -49,7 +49,7 @@ fn print_benchmark_result(
         }
     };
 
-    benchmark_results.set(serialize_string(name), serialize_duration(duration));
+    benchmark_results.set(serialize_string(name), serialize_duration(duration)).unwrap();
 }
 
 fn benchmark(mut f: impl FnMut()) -> Duration {
@@ -62,15 +62,15 @@ fn benchmark(mut f: impl FnMut()) -> Duration {
 }
 
 pub fn benchmark_kv_store(
-    mut benchmark_results: &mut kv_store::KVStore,
+    benchmark_results: &mut kv_store::KVStore,
     name: &str,
     samples: u32,
-    mut setup_f: impl FnMut(&mut kv_store::KVStore) -> (),
-    mut f: impl FnMut(&mut kv_store::KVStore) -> (),
+    mut setup_f: impl FnMut(&mut kv_store::KVStore),
+    mut f: impl FnMut(&mut kv_store::KVStore),
 ) {
     let mut duration = Duration::new(0, 0);
     for _ in 0..samples {
-        let mut kv: kv_store::KVStore = kv_store::KVStore::new(TMP_DIR);
+        let mut kv: kv_store::KVStore = kv_store::KVStore::open(TMP_DIR, kv_store::Options::default()).unwrap();
         setup_f(&mut kv);
         let d = benchmark(|| {
             f(&mut kv);
@@ -79,7 +79,7 @@ pub fn benchmark_kv_store(
         std::mem::drop(kv);
         fs::remove_dir_all(TMP_DIR).expect("Remove tmp folder");
     }
-    print_benchmark_result(&mut benchmark_results, name, duration / samples);
+    print_benchmark_result(benchmark_results, name, duration / samples);
 }
 
 fn serialize_duration(d: Duration) -> Vec<u8> {
@@ -110,7 +110,7 @@ enum Operation {
 
 pub fn benchmark_random_operations(
     name: &str,
-    mut benchmark_results: &mut kv_store::KVStore,
+    benchmark_results: &mut kv_store::KVStore,
     mut initial: Vec<(Vec<u8>, Vec<u8>)>,
     gets: Vec<(Vec<u8>, Vec<u8>)>,
     sets: Vec<(Vec<u8>, Vec<u8>)>,
@@ -133,22 +133,22 @@ pub fn benchmark_random_operations(
     shuffle_vec(&mut initial);
 
     benchmark_kv_store(
-        &mut benchmark_results,
+        benchmark_results,
         name,
         1,
         |kv| {
             for entry in &initial {
-                kv.set(entry.0.clone(), entry.1.clone());
+                kv.set(entry.0.clone(), entry.1.clone()).unwrap();
             }
         },
         |kv| {
             for op in &operations {
                 match op {
                     Operation::Write(pair) => {
-                        kv.set(pair.0.clone(), pair.1.clone());
+                        kv.set(pair.0.clone(), pair.1.clone()).unwrap();
                     }
                     Operation::Read(pair) => {
-                        black_box(kv.get(&pair.0));
+                        black_box(kv.get(&pair.0).unwrap());
                     }
                 }
             }
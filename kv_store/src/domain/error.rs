@@ -0,0 +1,56 @@
+use std::fmt;
+use std::io;
+
+use super::lsm_tree::encoding::Corruption;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Errors returned by the store.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // Data read from disk is not valid, like a block with a wrong checksum.
+    Corruption(String),
+    // The arguments of the call are not valid, like a key bigger than the maximum size.
+    InvalidArgument(String),
+    // Saving a memtable or compacting sstables failed in the background. Once this happens
+    // writes are rejected with this error, as there is no guarantee they would reach the disk.
+    // Reads keep working.
+    Background(String),
+    // The store was closed.
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption(message) => write!(f, "Corrupted data: {}", message),
+            Error::InvalidArgument(message) => write!(f, "Invalid argument: {}", message),
+            Error::Background(message) => write!(f, "Background error: {}", message),
+            Error::Closed => write!(f, "The store is closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// The lower layers use io::Error, with corruption marked by the Corruption payload.
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        match error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<Corruption>())
+        {
+            Some(corruption) => Error::Corruption(corruption.message.clone()),
+            None => Error::Io(error),
+        }
+    }
+}
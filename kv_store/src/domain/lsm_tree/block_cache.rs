@@ -0,0 +1,181 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::domain::stats::StatsCounters;
+
+// Cache of sstable blocks, shared by all the tables of a tree. Blocks are stored after checking
+// their checksum, so a hit doesn't touch the file at all.
+//
+// The cache is split in shards, each with its own lock and a part of the budget, so readers in
+// different threads rarely wait for each other. Each shard evicts its least recently used blocks
+// when it goes over budget.
+
+const NUM_SHARDS: usize = 16;
+// Memory used by a cached block besides its contents: the entry in the map and in the LRU list.
+const ENTRY_OVERHEAD: usize = 64;
+
+pub type Block = Arc<[u8]>;
+
+// Tables get an id from the cache when they are opened, and blocks are identified by the id of
+// their table and their offset in the file.
+type BlockKey = (u64, u64);
+
+pub struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+    next_table_id: AtomicU64,
+    stats: Arc<StatsCounters>,
+}
+
+#[derive(Default)]
+struct Shard {
+    capacity: usize,
+    usage: usize,
+    // Incremented on every access. The block with the lowest tick is the least recently used.
+    tick: u64,
+    blocks: HashMap<BlockKey, (Block, u64)>,
+    lru: BTreeMap<u64, BlockKey>,
+}
+
+impl BlockCache {
+    // `capacity` is the budget in bytes for all the shards. 0 disables the cache.
+    pub fn new(capacity: usize, stats: Arc<StatsCounters>) -> BlockCache {
+        BlockCache {
+            shards: (0..NUM_SHARDS)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        capacity: capacity / NUM_SHARDS,
+                        ..Shard::default()
+                    })
+                })
+                .collect(),
+            next_table_id: AtomicU64::new(0),
+            stats,
+        }
+    }
+
+    pub fn new_table_id(&self) -> u64 {
+        self.next_table_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn get(&self, table_id: u64, offset: u64) -> Option<Block> {
+        let key = (table_id, offset);
+        let mut shard = self.shard(key).lock().unwrap();
+        let block = shard.get(key);
+
+        StatsCounters::increment(if block.is_some() {
+            &self.stats.block_cache_hits
+        } else {
+            &self.stats.block_cache_misses
+        });
+        block
+    }
+
+    pub fn insert(&self, table_id: u64, offset: u64, block: Block) {
+        let key = (table_id, offset);
+        self.shard(key).lock().unwrap().insert(key, block);
+    }
+
+    fn shard(&self, (table_id, offset): BlockKey) -> &Mutex<Shard> {
+        // Blocks of a table are at similar offsets, so both parts are mixed to spread them.
+        let hash = (table_id ^ offset.rotate_left(32)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        &self.shards[(hash >> 32) as usize % NUM_SHARDS]
+    }
+}
+
+impl Shard {
+    fn get(&mut self, key: BlockKey) -> Option<Block> {
+        self.tick += 1;
+        let (block, last_use) = self.blocks.get_mut(&key)?;
+        self.lru.remove(last_use);
+        *last_use = self.tick;
+        self.lru.insert(self.tick, key);
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, block: Block) {
+        let block_charge = charge(&block);
+        // A block that doesn't fit would evict everything and be evicted right after.
+        if block_charge > self.capacity || self.blocks.contains_key(&key) {
+            return;
+        }
+
+        self.tick += 1;
+        self.usage += block_charge;
+        self.blocks.insert(key, (block, self.tick));
+        self.lru.insert(self.tick, key);
+
+        while self.usage > self.capacity {
+            let (_, oldest) = self.lru.pop_first().expect("Usage should come from blocks");
+            let (block, _) = self
+                .blocks
+                .remove(&oldest)
+                .expect("Blocks should be in the LRU");
+            self.usage -= charge(&block);
+        }
+    }
+}
+
+fn charge(block: &Block) -> usize {
+    block.len() + ENTRY_OVERHEAD
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: usize) -> Block {
+        vec![0u8; size].into()
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let stats = Arc::new(StatsCounters::default());
+        let cache = BlockCache::new(1024 * 1024, stats.clone());
+        let table = cache.new_table_id();
+
+        assert_eq!(cache.get(table, 0), None);
+        cache.insert(table, 0, block(100));
+        assert_eq!(cache.get(table, 0), Some(block(100)));
+        assert_eq!(cache.get(cache.new_table_id(), 0), None);
+
+        let stats = stats.snapshot();
+        assert_eq!(stats.block_cache_hits, 1);
+        assert_eq!(stats.block_cache_misses, 2);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut shard = Shard {
+            capacity: 3 * charge(&block(100)),
+            ..Shard::default()
+        };
+
+        shard.insert((0, 0), block(100));
+        shard.insert((0, 1), block(100));
+        shard.insert((0, 2), block(100));
+        // The first block is used, so the second one is the least recently used.
+        assert!(shard.get((0, 0)).is_some());
+        shard.insert((0, 3), block(100));
+
+        assert!(shard.get((0, 0)).is_some());
+        assert!(shard.get((0, 1)).is_none());
+        assert!(shard.get((0, 2)).is_some());
+        assert!(shard.get((0, 3)).is_some());
+        assert_eq!(shard.usage, 3 * charge(&block(100)));
+
+        // Blocks bigger than the shard are not cached.
+        shard.insert((0, 4), block(1000));
+        assert!(shard.get((0, 4)).is_none());
+        assert!(shard.get((0, 0)).is_some());
+    }
+}
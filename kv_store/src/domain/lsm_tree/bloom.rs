@@ -0,0 +1,124 @@
+// Bloom filter over the keys of an sstable. It can tell that a key is definitely not in the table
+// without reading it, so lookups of missing keys don't have to open every file.
+//
+// It uses double hashing: the k hash functions are derived from a single 64 bit hash as
+// h1 + i * h2. The hash is persisted with the table, so it is implemented here instead of using
+// the std Hasher, which is not guaranteed to be stable between Rust versions.
+//
+// Serialized as the bit array followed by one byte with the number of hash functions.
+
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn from_key_hashes(key_hashes: &[u64], bits_per_key: usize) -> BloomFilter {
+        // ln(2) * bits_per_key minimizes the false positive rate.
+        let num_hashes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+
+        // Very small filters have a high false positive rate, so use a minimum size.
+        let num_bits = (key_hashes.len() * bits_per_key).max(64);
+        let mut filter = BloomFilter {
+            bits: vec![0u8; num_bits.div_ceil(8)],
+            num_hashes,
+        };
+
+        for key_hash in key_hashes {
+            for bit in filter.bit_positions(*key_hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+
+        filter
+    }
+
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        let key_hash = hash(key);
+        self.bit_positions(key_hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_positions(&self, key_hash: u64) -> impl Iterator<Item = usize> {
+        let num_bits = (self.bits.len() * 8) as u64;
+        let h1 = key_hash >> 32;
+        let h2 = key_hash & 0xffff_ffff;
+        (0..self.num_hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ret = self.bits.clone();
+        ret.push(self.num_hashes as u8);
+        ret
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Option<BloomFilter> {
+        let (num_hashes, bits) = bytes.split_last()?;
+        if bits.is_empty() || *num_hashes == 0 {
+            return None;
+        }
+
+        Some(BloomFilter {
+            bits: bits.to_vec(),
+            num_hashes: *num_hashes as u32,
+        })
+    }
+}
+
+// FNV-1a followed by the murmur3 finalizer, so both halves of the hash are well mixed.
+pub fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^= h >> 33;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains_added_keys() {
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let hashes: Vec<u64> = keys.iter().map(|k| hash(k)).collect();
+        let filter = BloomFilter::from_key_hashes(&hashes, 10);
+
+        for key in &keys {
+            assert!(filter.may_contain(key));
+        }
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let hashes: Vec<u64> = (0..1000u32).map(|i| hash(&i.to_be_bytes())).collect();
+        let filter = BloomFilter::from_key_hashes(&hashes, 10);
+
+        let false_positives = (1000..11_000u32)
+            .filter(|i| filter.may_contain(&i.to_be_bytes()))
+            .count();
+        // With 10 bits per key the expected rate is about 1%.
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let hashes: Vec<u64> = (0..100u32).map(|i| hash(&i.to_be_bytes())).collect();
+        let filter = BloomFilter::from_key_hashes(&hashes, 10);
+        let filter = BloomFilter::deserialize(&filter.serialize()).expect("Valid filter");
+
+        for i in 0..100u32 {
+            assert!(filter.may_contain(&i.to_be_bytes()));
+        }
+        assert!(BloomFilter::deserialize(&[]).is_none());
+    }
+}
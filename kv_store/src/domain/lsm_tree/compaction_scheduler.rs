@@ -0,0 +1,233 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::levels::{Compaction, NUM_LEVELS};
+use super::{run_compaction, Storage};
+use crate::domain::stats::StatsCounters;
+
+// Pool of threads that compact the sstables in the background, independently of the flushes.
+// Workers pick the next compaction whenever the levels change or a compaction finishes:
+// - When level 0 has `l0_compaction_trigger` tables or a level is bigger than its limit.
+// - When no memtable has been saved for `idle_compaction_delay`, level 0 is compacted into level
+//   1 even if it has less tables, so reads have less tables to check.
+// - When a full compaction is requested, it waits for the running compactions, as it uses every
+//   level.
+//
+// Compactions that use different levels run at the same time. A level used by a running
+// compaction is busy and no other compaction is picked for it, so each compaction sees its
+// levels as if it ran alone.
+pub struct CompactionScheduler {
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+struct Shared {
+    storage: Storage,
+    stats: Arc<StatsCounters>,
+    idle_delay: Duration,
+    state: Mutex<State>,
+    // Notified when a memtable is saved, when a compaction finishes and when the state changes.
+    changed: Condvar,
+}
+
+struct State {
+    paused: bool,
+    shutdown: bool,
+    full_compaction_requested: bool,
+    // Levels that are inputs or output of a running compaction.
+    busy_levels: [bool; NUM_LEVELS],
+    running: usize,
+    // When the last memtable was saved, or when the store was opened.
+    last_flush: Instant,
+}
+
+impl CompactionScheduler {
+    pub fn new(storage: Storage, stats: Arc<StatsCounters>) -> Self {
+        let threads = storage.options.compaction_threads;
+        let shared = Arc::new(Shared {
+            idle_delay: storage.options.idle_compaction_delay,
+            storage,
+            stats,
+            state: Mutex::new(State {
+                paused: false,
+                shutdown: false,
+                full_compaction_requested: false,
+                busy_levels: [false; NUM_LEVELS],
+                running: 0,
+                last_flush: Instant::now(),
+            }),
+            changed: Condvar::new(),
+        });
+
+        let workers = (0..threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || shared.work())
+            })
+            .collect();
+
+        CompactionScheduler { shared, workers }
+    }
+
+    // Called after a memtable is saved, so the workers check if level 0 needs a compaction.
+    pub fn memtable_saved(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.last_flush = Instant::now();
+        self.shared.changed.notify_all();
+    }
+
+    // Merges every table into a single level once the running compactions finish.
+    pub fn request_full_compaction(&self) {
+        self.shared
+            .update(|state| state.full_compaction_requested = true);
+    }
+
+    // No new compactions are started until `resume` is called. Running compactions finish.
+    pub fn pause(&self) {
+        self.shared.update(|state| state.paused = true);
+    }
+
+    pub fn resume(&self) {
+        self.shared.update(|state| state.paused = false);
+    }
+
+    // Waits until no compaction is running and there is nothing left to compact, except for the
+    // idle trigger. It returns right away while compactions are paused or after a background
+    // error, as nothing else would be compacted.
+    pub fn wait_until_idle(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.running > 0 || self.shared.has_pending_work(&state) {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for CompactionScheduler {
+    // Compactions that are running finish, as their outputs would be left as unused files
+    // otherwise. The rest are picked again on the next start.
+    fn drop(&mut self) {
+        self.shared.update(|state| state.shutdown = true);
+        for worker in self.workers.drain(..) {
+            if let Err(e) = worker.join() {
+                println!("Error in compaction worker: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn update(&self, change: impl FnOnce(&mut State)) {
+        let mut state = self.state.lock().unwrap();
+        change(&mut state);
+        self.changed.notify_all();
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        while !state.shutdown {
+            let compaction = match self.next_compaction(&mut state) {
+                Some(compaction) => compaction,
+                None => {
+                    state = self.wait(state);
+                    continue;
+                }
+            };
+
+            let used_levels = compaction.input_level..=compaction.output_level;
+            for level in used_levels.clone() {
+                state.busy_levels[level] = true;
+            }
+            state.running += 1;
+            drop(state);
+
+            self.run(compaction);
+
+            state = self.state.lock().unwrap();
+            for level in used_levels {
+                state.busy_levels[level] = false;
+            }
+            state.running -= 1;
+            self.changed.notify_all();
+        }
+    }
+
+    // Waits for a change, or until the idle trigger is due.
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        if self.idle_delay == Duration::ZERO {
+            return self.changed.wait(state).unwrap();
+        }
+        let mut timeout = self.idle_delay.saturating_sub(state.last_flush.elapsed());
+        if timeout == Duration::ZERO {
+            // The trigger was already checked, so it can only fire again after another flush.
+            timeout = self.idle_delay;
+        }
+        self.changed.wait_timeout(state, timeout).unwrap().0
+    }
+
+    fn can_compact(&self, state: &State) -> bool {
+        !state.paused && self.storage.background_error.lock().unwrap().is_none()
+    }
+
+    fn has_pending_work(&self, state: &State) -> bool {
+        if !self.can_compact(state) {
+            return false;
+        }
+        state.full_compaction_requested
+            || self
+                .storage
+                .levels
+                .read()
+                .unwrap()
+                .pick_compaction(&state.busy_levels)
+                .is_some()
+    }
+
+    fn next_compaction(&self, state: &mut State) -> Option<Compaction> {
+        if !self.can_compact(state) {
+            return None;
+        }
+
+        let levels = self.storage.levels.read().unwrap();
+        if state.full_compaction_requested {
+            if state.running > 0 {
+                return None;
+            }
+            state.full_compaction_requested = false;
+            return levels.pick_full_compaction();
+        }
+
+        levels.pick_compaction(&state.busy_levels).or_else(|| {
+            let idle =
+                self.idle_delay != Duration::ZERO && state.last_flush.elapsed() >= self.idle_delay;
+            if idle {
+                levels.pick_idle_compaction(&state.busy_levels)
+            } else {
+                None
+            }
+        })
+    }
+
+    // A failed compaction leaves the levels as they were, but it is reported as a background
+    // error and no more compactions are run, as they would probably fail the same way.
+    fn run(&self, compaction: Compaction) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run_compaction(&self.storage, compaction)
+        }));
+        match result {
+            Ok(Ok(stats)) => {
+                StatsCounters::increment(&self.stats.compactions);
+                StatsCounters::add(&self.stats.dropped_tombstones, stats.dropped_tombstones);
+                StatsCounters::add(&self.stats.dropped_versions, stats.dropped_versions);
+            }
+            Ok(Err(e)) => {
+                println!("Error in compaction worker: {:?}", e);
+                self.storage.set_background_error(e.to_string());
+            }
+            Err(_) => self
+                .storage
+                .set_background_error(String::from("Compaction worker panicked")),
+        }
+    }
+}
@@ -1,89 +1,436 @@
-use std::io::prelude::*;
-use std::io::{self, Read, SeekFrom};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
 
-fn read_size<Tr: Read + Seek>(reader: &mut Tr, buffer: &mut Vec<u8>) -> io::Result<u16> {
-    if buffer.len() < 2 {
-        buffer.resize(2, 0);
+use super::crc32c::crc32c;
+use super::lz4;
+use crate::domain::options::Compression;
+use crate::domain::Value;
+
+// Every entry has a byte with its type before the value. Tombstones have no value, and expiring
+// data has the expiration time as a varint before it.
+const ENTRY_TYPE_DATA: u8 = 0;
+const ENTRY_TYPE_TOMBSTONE: u8 = 1;
+const ENTRY_TYPE_EXPIRING_DATA: u8 = 2;
+
+// Before entries had a type, deletions were stored as this random value.
+pub const LEGACY_TOMBSTONE: [u8; 32] = [
+    179, 210, 155, 16, 110, 229, 104, 202, 72, 124, 209, 13, 85, 192, 56, 71, 239, 10, 116, 199,
+    186, 205, 163, 143, 3, 43, 125, 16, 157, 22, 47, 244,
+];
+
+// Versions of the sstable format. Legacy tables can still be read, but new tables are always
+// written with blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FormatVersion {
+    // Entries without index nor footer, sizes stored as big endian u16, and deletions stored as
+    // LEGACY_TOMBSTONE.
+    Legacy,
+    // Indexed data blocks, each followed by its compression type and CRC32C. Sizes are stored as
+    // varints, and every entry has the sequence number of its write after the key and a byte
+    // with its type before the value. A key can have several entries, from newest to oldest.
+    Blocks,
+}
+
+// Data read from disk that is not valid. It is returned inside an io::Error of kind InvalidData,
+// and the KVStore turns it into Error::Corruption.
+#[derive(Debug)]
+pub struct Corruption {
+    pub message: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Corrupted data: {}", self.message)
     }
+}
+
+impl Error for Corruption {}
+
+pub fn corruption_error<S: Into<String>>(message: S) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        Corruption {
+            message: message.into(),
+        },
+    )
+}
+
+// Sizes read from disk bigger than this can only come from corrupted data. Checking it avoids
+// allocating huge buffers.
+const MAX_DATUM_SIZE: u64 = u32::MAX as u64;
 
-    reader.read_exact(&mut buffer[..2])?;
+fn read_legacy_size<Tr: Read>(reader: &mut Tr) -> io::Result<u64> {
     let mut size_bytes = [0u8; 2];
-    size_bytes.clone_from_slice(&buffer[..2]);
-    let size = u16::from_be_bytes(size_bytes);
+    reader.read_exact(&mut size_bytes)?;
+    Ok(u16::from_be_bytes(size_bytes) as u64)
+}
 
-    Ok(size)
+// LEB128: 7 bits per byte, least significant group first, with the high bit set in all bytes
+// but the last.
+fn read_varint<Tr: Read>(reader: &mut Tr) -> io::Result<u64> {
+    let mut result: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        result |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(result);
+        }
+
+        shift += 7;
+        if shift >= 64 {
+            return Err(corruption_error("Varint too long"));
+        }
+    }
 }
 
-pub fn read_next_datum<Tr: Read + Seek>(
+fn serialize_varint(mut n: u64, out: &mut Vec<u8>) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+// Reads a size prefixed datum into the buffer and returns its size.
+pub fn read_next_datum<Tr: Read>(
     reader: &mut Tr,
     buffer: &mut Vec<u8>,
+    version: FormatVersion,
 ) -> io::Result<usize> {
-    let size = read_size(reader, buffer)?;
+    let size = match version {
+        FormatVersion::Legacy => read_legacy_size(reader)?,
+        FormatVersion::Blocks => read_varint(reader)?,
+    };
 
-    if buffer.len() < size as usize {
-        buffer.resize(size as usize, 0);
+    if size > MAX_DATUM_SIZE {
+        return Err(corruption_error("Datum size too big"));
+    }
+    let size = size as usize;
+
+    if buffer.len() < size {
+        buffer.resize(size, 0);
     }
 
     // Read value
-    reader.read_exact(&mut buffer[..(size as usize)])?;
-    Ok(size as usize)
+    reader.read_exact(&mut buffer[..size])?;
+    Ok(size)
 }
 
-pub fn find_value<Tr: Read + Seek>(reader: &mut Tr, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
-    // 256 seams a reasonable nubmber to reserve, although values could be as big as
-    //     u16::max_value()
-    let mut buffer: Vec<u8> = Vec::with_capacity(256);
+// Reads the next entry, in the format given by `version`, and returns its key, sequence number
+// and value. Legacy entries have sequence number 0.
+pub fn read_entry<Tr: Read>(
+    reader: &mut Tr,
+    buffer: &mut Vec<u8>,
+    version: FormatVersion,
+) -> io::Result<(Vec<u8>, u64, Value)> {
+    let key_size = read_next_datum(reader, buffer, version)?;
+    let key = buffer[..key_size].to_vec();
 
-    loop {
-        let key_size = read_next_datum(reader, &mut buffer)?;
-        let key_found = &buffer[..key_size];
-        let key_found = key == key_found;
+    if version == FormatVersion::Legacy {
+        let value_size = read_next_datum(reader, buffer, version)?;
+        let value = &buffer[..value_size];
+        if value == LEGACY_TOMBSTONE {
+            return Ok((key, 0, Value::Tombstone));
+        }
+        return Ok((key, 0, Value::Data(value.to_vec())));
+    }
+
+    let sequence = read_varint(reader)?;
+    let mut entry_type = [0u8; 1];
+    reader.read_exact(&mut entry_type)?;
+    match entry_type[0] {
+        ENTRY_TYPE_DATA => {
+            let value_size = read_next_datum(reader, buffer, version)?;
+            Ok((key, sequence, Value::Data(buffer[..value_size].to_vec())))
+        }
+        ENTRY_TYPE_TOMBSTONE => Ok((key, sequence, Value::Tombstone)),
+        ENTRY_TYPE_EXPIRING_DATA => {
+            let expires_at = read_varint(reader)?;
+            let value_size = read_next_datum(reader, buffer, version)?;
+            let data = buffer[..value_size].to_vec();
+            Ok((key, sequence, Value::Expiring { data, expires_at }))
+        }
+        _ => Err(corruption_error("Unknown entry type")),
+    }
+}
+
+// Searches the newest version of the key written at or before `sequence` in the entries of the
+// reader, which must end at the end of an entry. Versions of a key are stored from newest to
+// oldest, so it is the first one found.
+pub fn find_value<Tr: Read>(
+    reader: &mut io::Take<Tr>,
+    key: &[u8],
+    sequence: u64,
+    version: FormatVersion,
+) -> io::Result<Option<Value>> {
+    // 256 seams a reasonable nubmber to reserve, although values can be much bigger
+    let mut buffer: Vec<u8> = Vec::with_capacity(256);
 
-        if key_found {
-            let value_size = read_next_datum(reader, &mut buffer)?;
-            let value = buffer[..(value_size as usize)].to_vec();
+    while reader.limit() > 0 {
+        let (key_found, sequence_found, value) = read_entry(reader, &mut buffer, version)?;
+        if key == &key_found[..] && sequence_found <= sequence {
             return Ok(Some(value));
-        } else {
-            read_next_datum(reader, &mut buffer)?;
         }
     }
+    Ok(None)
 }
 
-// Max size is 64kB
-fn serialize_size(size: u16) -> [u8; 2] {
-    size.to_be_bytes()
+// Datums are always written in the latest format. Size limits are enforced by the KVStore before
+// data reaches this point.
+pub fn serialize_datum(datum: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(datum.len() + 10);
+    serialize_varint(datum.len() as u64, &mut ret);
+    ret.extend_from_slice(datum);
+    ret
 }
 
-pub fn serialize_entry(key: &[u8], value: &[u8]) -> Vec<u8> {
+pub fn serialize_entry(key: &[u8], sequence: u64, value: &Value) -> Vec<u8> {
     let mut ret = Vec::new();
-    if key.len() > u16::max_value() as usize {
-        panic!("Key bigger than 64kB");
-    }
 
-    if value.len() > u16::max_value() as usize {
-        panic!("Value bigger than 64kB");
+    ret.append(&mut serialize_datum(key));
+    serialize_varint(sequence, &mut ret);
+    match value {
+        Value::Data(data) => {
+            ret.push(ENTRY_TYPE_DATA);
+            ret.append(&mut serialize_datum(data));
+        }
+        Value::Tombstone => ret.push(ENTRY_TYPE_TOMBSTONE),
+        Value::Expiring { data, expires_at } => {
+            ret.push(ENTRY_TYPE_EXPIRING_DATA);
+            serialize_varint(*expires_at, &mut ret);
+            ret.append(&mut serialize_datum(data));
+        }
     }
-
-        ret.extend_from_slice(&serialize_size(key.len() as u16));
-        ret.append(&mut key.to_vec());
-        ret.extend_from_slice(&serialize_size(value.len() as u16));
-        ret.append(&mut value.to_vec());
     ret
 }
 
-pub fn serialize_values(values: &[(&Vec<u8>, &Vec<u8>)]) -> Vec<u8> {
-    let mut ret = Vec::new();
-    for p in values {
-        if p.0.len() > u16::max_value() as usize {
-            panic!("Key bigger than 64kB");
+// Random number written at the end of every indexed sstable. Files without it are legacy
+// sstables, which can only be read linearly.
+const FOOTER_MAGIC: u64 = 0x8f4e_2b1a_d03c_77ea;
+pub const BLOCK_HANDLE_SIZE: usize = 16;
+pub const FOOTER_SIZE: usize = 2 * BLOCK_HANDLE_SIZE + 8;
+
+const BLOCK_TYPE_UNCOMPRESSED: u8 = 0;
+const BLOCK_TYPE_LZ4: u8 = 1;
+
+// Bytes after each block with its type and checksum. Block handles don't include them.
+pub const BLOCK_TRAILER_SIZE: usize = 5;
+
+// Returns the bytes to write for the block, which may be compressed, and its trailer. Blocks
+// that don't shrink at least 1/8 are stored uncompressed, as decompressing them is not worth it.
+pub fn serialize_block(
+    block: &[u8],
+    compression: Compression,
+) -> (Vec<u8>, [u8; BLOCK_TRAILER_SIZE]) {
+    let compressed = match compression {
+        Compression::None => None,
+        Compression::Lz4 => {
+            Some(lz4::compress(block)).filter(|compressed| compressed.len() < block.len() * 7 / 8)
         }
+    };
+    let (contents, block_type) = match compressed {
+        Some(compressed) => (compressed, BLOCK_TYPE_LZ4),
+        None => (block.to_vec(), BLOCK_TYPE_UNCOMPRESSED),
+    };
+
+    let mut trailer = [block_type, 0, 0, 0, 0];
+    trailer[1..].copy_from_slice(&block_checksum(&contents, block_type).to_be_bytes());
+    (contents, trailer)
+}
+
+// The checksum covers the type too, so a corrupted type is detected.
+fn block_checksum(contents: &[u8], block_type: u8) -> u32 {
+    let mut checksummed = Vec::with_capacity(contents.len() + 1);
+    checksummed.extend_from_slice(contents);
+    checksummed.push(block_type);
+    crc32c(&checksummed)
+}
 
-        if p.1.len() > u16::max_value() as usize {
-            panic!("Value bigger than 64kB");
+// Checks the block read from disk against its trailer, and decompresses it.
+pub fn deserialize_block(contents: Vec<u8>, trailer: &[u8]) -> io::Result<Vec<u8>> {
+    let block_type = match trailer.len() {
+        BLOCK_TRAILER_SIZE
+            if trailer[1..] == block_checksum(&contents, trailer[0]).to_be_bytes() =>
+        {
+            trailer[0]
         }
+        _ => return Err(corruption_error("Block checksum mismatch")),
+    };
+
+    match block_type {
+        BLOCK_TYPE_UNCOMPRESSED => Ok(contents),
+        BLOCK_TYPE_LZ4 => {
+            lz4::decompress(&contents).ok_or_else(|| corruption_error("Invalid compressed block"))
+        }
+        _ => Err(corruption_error(format!(
+            "Unknown block type {}",
+            block_type
+        ))),
+    }
+}
+
+// Position of a block in the sstable file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+pub fn serialize_block_handle(handle: &BlockHandle) -> [u8; BLOCK_HANDLE_SIZE] {
+    let mut ret = [0u8; BLOCK_HANDLE_SIZE];
+    ret[..8].copy_from_slice(&handle.offset.to_be_bytes());
+    ret[8..].copy_from_slice(&handle.size.to_be_bytes());
+    ret
+}
 
-        ret.append(&mut serialize_entry(p.0, p.1));
+pub fn deserialize_block_handle(bytes: &[u8]) -> io::Result<BlockHandle> {
+    if bytes.len() != BLOCK_HANDLE_SIZE {
+        return Err(corruption_error("Block handle with wrong size"));
     }
 
+    let mut offset_bytes = [0u8; 8];
+    offset_bytes.copy_from_slice(&bytes[..8]);
+    let mut size_bytes = [0u8; 8];
+    size_bytes.copy_from_slice(&bytes[8..]);
+    Ok(BlockHandle {
+        offset: u64::from_be_bytes(offset_bytes),
+        size: u64::from_be_bytes(size_bytes),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Footer {
+    pub index: BlockHandle,
+    // Empty if the table was written without bloom filter.
+    pub filter: BlockHandle,
+}
+
+// The footer contains the handles of the index and filter blocks followed by the magic number.
+pub fn serialize_footer(footer: &Footer) -> [u8; FOOTER_SIZE] {
+    let mut ret = [0u8; FOOTER_SIZE];
+    ret[..BLOCK_HANDLE_SIZE].copy_from_slice(&serialize_block_handle(&footer.index));
+    ret[BLOCK_HANDLE_SIZE..2 * BLOCK_HANDLE_SIZE]
+        .copy_from_slice(&serialize_block_handle(&footer.filter));
+    ret[2 * BLOCK_HANDLE_SIZE..].copy_from_slice(&FOOTER_MAGIC.to_be_bytes());
     ret
 }
+
+// Returns None if the bytes are not a footer, which means that the file uses the legacy format.
+pub fn deserialize_footer(bytes: &[u8; FOOTER_SIZE]) -> Option<Footer> {
+    let mut magic_bytes = [0u8; 8];
+    magic_bytes.copy_from_slice(&bytes[2 * BLOCK_HANDLE_SIZE..]);
+    if u64::from_be_bytes(magic_bytes) != FOOTER_MAGIC {
+        return None;
+    }
+
+    Some(Footer {
+        index: deserialize_block_handle(&bytes[..BLOCK_HANDLE_SIZE]).ok()?,
+        filter: deserialize_block_handle(&bytes[BLOCK_HANDLE_SIZE..2 * BLOCK_HANDLE_SIZE]).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_varint() {
+        for n in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            65_535,
+            65_536,
+            u32::MAX as u64,
+            u64::MAX,
+        ] {
+            let mut bytes = Vec::new();
+            serialize_varint(n, &mut bytes);
+            assert_eq!(read_varint(&mut Cursor::new(bytes)).unwrap(), n);
+        }
+    }
+
+    #[test]
+    fn test_entries_bigger_than_64kb() {
+        let key = vec![7u8; 70_000];
+        let value = Value::Data(vec![3u8; 1_000_000]);
+        let bytes = serialize_entry(&key, 42, &value);
+
+        let mut buffer = Vec::new();
+        let entry = read_entry(&mut Cursor::new(bytes), &mut buffer, FormatVersion::Blocks);
+        assert_eq!(entry.unwrap(), (key, 42, value));
+    }
+
+    #[test]
+    fn test_entry_sequences() {
+        // Versions of a key go from newest to oldest.
+        let mut bytes = serialize_entry(b"a", 2, &Value::Data(b"c".to_vec()));
+        bytes.extend(serialize_entry(b"a", 1, &Value::Tombstone));
+        bytes.extend(serialize_entry(b"b", 3, &Value::Tombstone));
+        let size = bytes.len() as u64;
+        let find = |sequence| {
+            let mut reader = Cursor::new(bytes.clone()).take(size);
+            find_value(&mut reader, b"a", sequence, FormatVersion::Blocks).unwrap()
+        };
+        assert_eq!(find(0), None);
+        assert_eq!(find(1), Some(Value::Tombstone));
+        assert_eq!(find(u64::MAX), Some(Value::Data(b"c".to_vec())));
+    }
+
+    #[test]
+    fn test_compressed_blocks() {
+        let block: Vec<u8> = (0..100)
+            .flat_map(|i| format!("key {} value {}", i % 10, i % 10).into_bytes())
+            .collect();
+
+        let (contents, trailer) = serialize_block(&block, Compression::Lz4);
+        assert!(contents.len() < block.len());
+        assert_eq!(
+            deserialize_block(contents.clone(), &trailer).unwrap(),
+            block
+        );
+
+        // The type is covered by the checksum.
+        let mut wrong_type = trailer;
+        wrong_type[0] = BLOCK_TYPE_UNCOMPRESSED;
+        assert!(deserialize_block(contents, &wrong_type).is_err());
+
+        let (contents, trailer) = serialize_block(&block, Compression::None);
+        assert_eq!(contents, block);
+        assert_eq!(deserialize_block(contents, &trailer).unwrap(), block);
+
+        // Blocks that don't compress are stored as they are.
+        let random: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+        let (contents, trailer) = serialize_block(&random, Compression::Lz4);
+        assert_eq!(contents, random);
+        assert_eq!(trailer[0], BLOCK_TYPE_UNCOMPRESSED);
+    }
+
+    #[test]
+    fn test_read_legacy_datum() {
+        let bytes = vec![0, 3, b'a', b'b', b'c'];
+        let mut buffer = Vec::new();
+        let size = read_next_datum(&mut Cursor::new(bytes), &mut buffer, FormatVersion::Legacy);
+        assert_eq!(size.unwrap(), 3);
+        assert_eq!(&buffer[..3], b"abc");
+    }
+
+    #[test]
+    fn test_expiring_entries() {
+        let value = Value::Expiring {
+            data: b"b".to_vec(),
+            expires_at: 1_700_000_000_000,
+        };
+        let bytes = serialize_entry(b"a", 7, &value);
+
+        let mut buffer = Vec::new();
+        let entry = read_entry(&mut Cursor::new(bytes), &mut buffer, FormatVersion::Blocks);
+        assert_eq!(entry.unwrap(), (b"a".to_vec(), 7, value));
+    }
+}
@@ -0,0 +1,420 @@
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+use super::block_cache::BlockCache;
+use super::manifest::{Manifest, ManifestState, VersionEdit};
+use super::sstable::SSTable;
+use super::{file_number, sstable_path, SSTABLE_EXTENSION};
+use crate::domain::options::Options;
+
+// Leveled compaction, like LevelDB:
+//  - Level 0 has the tables flushed from memtables, so their key ranges may overlap. They are
+//    ordered from oldest to newest.
+//  - Levels 1 and above are sorted runs: their tables don't overlap, and they are ordered by key.
+//    Each level can hold `level_size_ratio` times more bytes than the previous one.
+//
+// When level 0 has too many tables, all of them are merged with the overlapping tables of level
+// 1. When a level is too big, one of its tables is merged with the overlapping tables of the next
+// level. Each compaction only rewrites a small part of the data. Compactions that use different
+// levels can run at the same time, so picking one skips the levels used by the running ones.
+//
+// A contiguous range of level 0 tables can also be merged into a table that stays in level 0, in
+// the place of the tables it replaces, so it keeps being newer than the tables before it and
+// older than the ones after it. This is done while level 1 is busy, so reads don't have to
+// check more and more tables in the meantime.
+//
+// Every change to the levels is recorded in the manifest, so the shape of the tree survives
+// restarts.
+
+pub const NUM_LEVELS: usize = 7;
+const LEVEL_1_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+pub struct Levels {
+    levels: Vec<Vec<SSTable>>,
+    manifest: Manifest,
+    // Number of level 0 tables that starts a compaction into level 1.
+    l0_compaction_trigger: usize,
+    // Each level can hold this many times more bytes than the previous one.
+    level_size_ratio: u64,
+    // Sequence number of the newest write saved in the tables.
+    last_sequence: u64,
+}
+
+// Tables to merge and the level where the result goes.
+#[derive(Debug)]
+pub struct Compaction {
+    // Ordered from oldest to newest, as expected by MergingIterator.
+    pub inputs: Vec<SSTable>,
+    // The inputs come from this level to `output_level`, which are all used by the compaction.
+    pub input_level: usize,
+    pub output_level: usize,
+    // A single table that doesn't overlap anything in the next level can be moved there without
+    // rewriting it.
+    pub trivial_move: bool,
+    // Tables that are not inputs but may have older versions of the keys of the inputs.
+    pub older_tables: Vec<SSTable>,
+}
+
+impl Compaction {
+    // Whether the compaction has all the versions of the key. Then a version that hides older
+    // ones, like a tombstone, isn't needed if the compaction doesn't keep any older one.
+    pub fn has_oldest_versions_of(&self, key: &[u8]) -> bool {
+        !self
+            .older_tables
+            .iter()
+            .any(|table| table.overlaps(key, key))
+    }
+}
+
+impl Levels {
+    // Loads the tables listed in the manifest of the directory, and returns them with the number
+    // of the next file to create. The manifest is rewritten with only the current state, so it
+    // doesn't grow forever. Files not in the manifest are removed afterwards.
+    //
+    // Directories written before the manifest existed don't have one, so it is created from the
+    // files in the directory.
+    pub fn open(
+        dir: &str,
+        options: &Options,
+        cache: &Arc<BlockCache>,
+    ) -> io::Result<(Levels, u64)> {
+        let state = match Manifest::read(dir)? {
+            Some(state) => state,
+            None => state_without_manifest(dir)?,
+        };
+
+        let mut levels = vec![Vec::new(); NUM_LEVELS];
+        for (level, number) in &state.tables {
+            let path = sstable_path(dir, *number);
+            println!("Found sstable in level {}: {}", level, path);
+            levels[*level].push(SSTable::open(path, options.read_buffer_size, cache)?);
+        }
+        // Level 0 keeps the order of the manifest, which is the age of the tables.
+        for level in &mut levels[1..] {
+            level.sort_by(|a, b| a.key_range().cmp(&b.key_range()));
+        }
+
+        let manifest = Manifest::create(dir, &state)?;
+        remove_unused_files(dir, &state)?;
+
+        Ok((
+            Levels {
+                levels,
+                manifest,
+                l0_compaction_trigger: options.l0_compaction_trigger,
+                level_size_ratio: options.level_size_ratio as u64,
+                last_sequence: state.last_sequence,
+            },
+            state.next_file_number,
+        ))
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    pub fn level(&self, level: usize) -> &[SSTable] {
+        &self.levels[level]
+    }
+
+    pub fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    // `next_file_number` is saved in the manifest with the change, so numbers used by tables
+    // being written are never reused. `last_sequence` is the newest sequence number in the table,
+    // which is also saved so sequence numbers keep growing after the write-ahead logs are
+    // removed.
+    pub fn add_flushed_table(
+        &mut self,
+        sstable: SSTable,
+        next_file_number: u64,
+        last_sequence: u64,
+    ) -> io::Result<()> {
+        let last_sequence = self.last_sequence.max(last_sequence);
+        self.manifest.append(&VersionEdit {
+            added: vec![(0, table_number(&sstable))],
+            removed: Vec::new(),
+            next_file_number,
+            last_sequence,
+        })?;
+        self.levels[0].push(sstable);
+        self.last_sequence = last_sequence;
+        Ok(())
+    }
+
+    // Tables that may contain the key, from newest to oldest: every table of level 0, and at
+    // most one table of each other level.
+    pub fn tables_for_key(&self, key: &[u8]) -> Vec<&SSTable> {
+        let mut tables: Vec<&SSTable> = self.levels[0].iter().rev().collect();
+
+        for level in &self.levels[1..] {
+            let index = level.partition_point(|table| {
+                table
+                    .key_range()
+                    .is_some_and(|(_, largest_key)| largest_key < key)
+            });
+            if let Some(table) = level.get(index) {
+                if table.overlaps(key, key) {
+                    tables.push(table);
+                }
+            }
+        }
+
+        tables
+    }
+
+    // Returns the next compaction to run, or None if every level is within its limits. Levels
+    // marked in `busy_levels` are used by running compactions, so compactions that use them are
+    // not picked.
+    pub fn pick_compaction(&self, busy_levels: &[bool; NUM_LEVELS]) -> Option<Compaction> {
+        let is_free = |level: usize| !busy_levels[level] && !busy_levels[level + 1];
+
+        if !busy_levels[0] && self.levels[0].len() >= self.l0_compaction_trigger {
+            if !busy_levels[1] {
+                return Some(self.compaction_into_next_level(0, self.levels[0].clone()));
+            }
+            if let Some(compaction) = self.compaction_of_similar_level_0_tables() {
+                return Some(compaction);
+            }
+        }
+
+        // The last level has no limit.
+        for level in (1..NUM_LEVELS - 1).filter(|level| is_free(*level)) {
+            let level_size: u64 = self.levels[level].iter().map(SSTable::file_size).sum();
+            if level_size > self.max_level_bytes(level) {
+                // The oldest table has the lowest number. Picking it makes compactions go
+                // through the whole key range over time.
+                let table = self.levels[level]
+                    .iter()
+                    .min_by(|a, b| a.path.cmp(&b.path))?
+                    .clone();
+                return Some(self.compaction_into_next_level(level, vec![table]));
+            }
+        }
+
+        None
+    }
+
+    // Compacts level 0 into level 1 even if it has fewer tables than the trigger, so reads have
+    // less tables to check. Used when the store is idle.
+    pub fn pick_idle_compaction(&self, busy_levels: &[bool; NUM_LEVELS]) -> Option<Compaction> {
+        if busy_levels[0] || busy_levels[1] || self.levels[0].is_empty() {
+            return None;
+        }
+        Some(self.compaction_into_next_level(0, self.levels[0].clone()))
+    }
+
+    // Merges the level 0 tables in the range, which are indexes from oldest to newest, into a
+    // table that replaces them in level 0. None if the range has no tables.
+    pub fn compaction_of_level_0_range(&self, range: Range<usize>) -> Option<Compaction> {
+        let inputs = self.levels[0].get(range.clone())?.to_vec();
+        if inputs.is_empty() {
+            return None;
+        }
+
+        // Older versions of the keys can be in older tables of level 0 and in deeper levels.
+        let older_tables = overlapping_tables(
+            self.levels[0][..range.start]
+                .iter()
+                .chain(self.levels[1..].iter().flatten()),
+            &inputs,
+        );
+        Some(Compaction {
+            inputs,
+            input_level: 0,
+            output_level: 0,
+            trivial_move: false,
+            older_tables,
+        })
+    }
+
+    // Merges the two adjacent level 0 tables with the most similar sizes, so the bigger tables
+    // aren't rewritten again and again. The output is a single table, so level 0 has one table
+    // less afterwards.
+    fn compaction_of_similar_level_0_tables(&self) -> Option<Compaction> {
+        let size_ratio = |index: usize| {
+            let first = self.levels[0][index].file_size().max(1);
+            let second = self.levels[0][index + 1].file_size().max(1);
+            first.max(second) as f64 / first.min(second) as f64
+        };
+        let start = (0..self.levels[0].len().saturating_sub(1))
+            .min_by(|a, b| size_ratio(*a).total_cmp(&size_ratio(*b)))?;
+        self.compaction_of_level_0_range(start..start + 2)
+            .filter(|compaction| compaction.inputs.len() > 1)
+    }
+
+    // Merges every table into the deepest level that has data. It uses every level, so it can't
+    // run with other compactions.
+    pub fn pick_full_compaction(&self) -> Option<Compaction> {
+        let output_level = (1..NUM_LEVELS)
+            .rev()
+            .find(|level| !self.levels[*level].is_empty())
+            .unwrap_or(1);
+        // Deeper levels have older data.
+        let inputs: Vec<SSTable> = self.levels.iter().rev().flatten().cloned().collect();
+        if inputs.is_empty() {
+            return None;
+        }
+
+        Some(Compaction {
+            inputs,
+            input_level: 0,
+            output_level,
+            trivial_move: false,
+            older_tables: Vec::new(),
+        })
+    }
+
+    fn compaction_into_next_level(&self, level: usize, tables: Vec<SSTable>) -> Compaction {
+        // Tables of the next level are older than the ones being pushed down, so they go first.
+        let mut inputs = overlapping_tables(self.levels[level + 1].iter(), &tables);
+        let trivial_move = inputs.is_empty() && tables.len() == 1;
+        inputs.extend(tables);
+
+        let older_tables = overlapping_tables(self.levels[level + 2..].iter().flatten(), &inputs);
+        Compaction {
+            inputs,
+            input_level: level,
+            output_level: level + 1,
+            trivial_move,
+            older_tables,
+        }
+    }
+
+    fn max_level_bytes(&self, level: usize) -> u64 {
+        LEVEL_1_MAX_BYTES.saturating_mul(self.level_size_ratio.saturating_pow(level as u32 - 1))
+    }
+
+    // Replaces the inputs of the compaction with its outputs. Returns the input tables that are
+    // no longer used, whose files can be removed.
+    pub fn apply(
+        &mut self,
+        compaction: &Compaction,
+        outputs: Vec<SSTable>,
+        next_file_number: u64,
+    ) -> io::Result<Vec<SSTable>> {
+        self.manifest.append(&VersionEdit {
+            added: outputs
+                .iter()
+                .map(|output| (compaction.output_level, table_number(output)))
+                .collect(),
+            removed: compaction.inputs.iter().map(table_number).collect(),
+            next_file_number,
+            last_sequence: self.last_sequence,
+        })?;
+
+        // Tables before the first input are not removed, so its position stays the same.
+        let level_0_position = self.levels[0]
+            .iter()
+            .position(|table| compaction.inputs.contains(table));
+        for level in &mut self.levels {
+            level.retain(|table| !compaction.inputs.contains(table));
+        }
+
+        let output_level = &mut self.levels[compaction.output_level];
+        if compaction.output_level == 0 {
+            // Level 0 is ordered by age, and the outputs have the age of the inputs.
+            let position = level_0_position.expect("Level 0 outputs come from level 0");
+            output_level.splice(position..position, outputs);
+        } else {
+            output_level.extend(outputs);
+            output_level.sort_by(|a, b| a.key_range().cmp(&b.key_range()));
+        }
+
+        Ok(compaction
+            .inputs
+            .iter()
+            .filter(|input| !output_level.contains(input))
+            .cloned()
+            .collect())
+    }
+}
+
+// The candidates that overlap the key range covered by `tables`.
+fn overlapping_tables<'a>(
+    candidates: impl Iterator<Item = &'a SSTable>,
+    tables: &[SSTable],
+) -> Vec<SSTable> {
+    let smallest_key = tables
+        .iter()
+        .filter_map(|table| table.key_range().map(|(smallest, _)| smallest))
+        .min();
+    let largest_key = tables
+        .iter()
+        .filter_map(|table| table.key_range().map(|(_, largest)| largest))
+        .max();
+
+    match smallest_key.zip(largest_key) {
+        Some((smallest_key, largest_key)) => candidates
+            .filter(|table| table.overlaps(smallest_key, largest_key))
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn table_number(sstable: &SSTable) -> u64 {
+    file_number(&sstable.path).expect("Sstables should have numbered names")
+}
+
+// Numbers of the sstable files in the directory.
+fn sstable_file_numbers(dir: &str) -> io::Result<Vec<u64>> {
+    let mut numbers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SSTABLE_EXTENSION) {
+            if let Some(number) = path.to_str().and_then(file_number) {
+                numbers.push(number);
+            }
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+// State of a directory without manifest. It is older than levels and sequence numbers, so all its
+// tables are in level 0, ordered by number.
+fn state_without_manifest(dir: &str) -> io::Result<ManifestState> {
+    let numbers = sstable_file_numbers(dir)?;
+    let next_file_number = numbers.last().map_or(0, |number| number + 1);
+    let tables = numbers.into_iter().map(|number| (0, number)).collect();
+
+    Ok(ManifestState {
+        tables,
+        next_file_number,
+        last_sequence: 0,
+    })
+}
+
+// Removes the files of tables that are not live: outputs of flushes and compactions that didn't
+// finish, inputs of compactions that did, and temporary files. It runs after the manifest is
+// rewritten, so the files it removes are never needed again.
+fn remove_unused_files(dir: &str, state: &ManifestState) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(file_name) => file_name,
+            None => continue,
+        };
+
+        let unused = if file_name.ends_with(&format!(".{}", SSTABLE_EXTENSION)) {
+            let number = path.to_str().and_then(file_number);
+            !state
+                .tables
+                .iter()
+                .any(|(_, live_number)| Some(*live_number) == number)
+        } else {
+            file_name.ends_with(&format!(".{}.tmp", SSTABLE_EXTENSION))
+        };
+
+        if unused {
+            println!("Removing unused file: {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
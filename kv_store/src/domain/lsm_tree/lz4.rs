@@ -0,0 +1,216 @@
+// LZ4 block format compression. Like the CRC32C, it is implemented here so the crate doesn't need
+// dependencies. The compressor is the simple greedy one: it looks for 4 byte sequences seen
+// before with a hash table, and extends each match as much as possible.
+//
+// A compressed block is a list of sequences, each made of:
+//  - A token: the number of literals in the high 4 bits, and the match length minus 4 in the low
+//    4 bits. 15 means that more length bytes follow, adding up to a byte smaller than 255.
+//  - The literals, copied as they are.
+//  - The offset of the match, 2 bytes little endian, and the rest of the match length.
+// The last sequence only has literals. The uncompressed size is prepended as 4 bytes little
+// endian, as the format doesn't include it.
+
+const MIN_MATCH: usize = 4;
+// The format requires the last 5 bytes to be literals, and the last match to start at least 12
+// bytes before the end.
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65_535;
+const HASH_LOG: u32 = 14;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(4 + input.len() + input.len() / 255 + 16);
+    output.extend_from_slice(&(input.len() as u32).to_le_bytes());
+
+    // Last position of each hashed 4 byte sequence.
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        let end_limit = input.len() - LAST_LITERALS;
+        while pos < match_limit {
+            let sequence = read_u32(input, pos);
+            let hash = hash(sequence);
+            let candidate = table[hash];
+            table[hash] = pos;
+
+            if candidate < pos
+                && pos - candidate <= MAX_OFFSET
+                && read_u32(input, candidate) == sequence
+            {
+                let mut length = MIN_MATCH;
+                while pos + length < end_limit && input[candidate + length] == input[pos + length] {
+                    length += 1;
+                }
+                write_sequence(&mut output, &input[anchor..pos], pos - candidate, length);
+                pos += length;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+
+    let literals = &input[anchor..];
+    write_token_and_literals(&mut output, literals, 0);
+    output
+}
+
+// None if the input is not a valid compressed block.
+pub fn decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let size_bytes = input.get(..4)?;
+    let size =
+        u32::from_le_bytes([size_bytes[0], size_bytes[1], size_bytes[2], size_bytes[3]]) as usize;
+    // Each byte can't expand to more than 255, so a bigger size comes from corrupted data and
+    // would allocate a huge buffer.
+    if size > input.len() * 255 {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(size);
+    let mut pos = 4;
+    loop {
+        let token = *input.get(pos)?;
+        pos += 1;
+
+        let literals_length = read_length(input, &mut pos, (token >> 4) as usize)?;
+        let literals = input.get(pos..pos.checked_add(literals_length)?)?;
+        if output.len() + literals.len() > size {
+            return None;
+        }
+        output.extend_from_slice(literals);
+        pos += literals_length;
+
+        if pos == input.len() {
+            break;
+        }
+
+        let offset_bytes = input.get(pos..pos + 2)?;
+        let offset = u16::from_le_bytes([offset_bytes[0], offset_bytes[1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > output.len() {
+            return None;
+        }
+
+        let match_length = read_length(input, &mut pos, (token & 0x0f) as usize)? + MIN_MATCH;
+        if output.len() + match_length > size {
+            return None;
+        }
+        // The match can overlap the bytes it produces, so it is copied byte by byte.
+        let start = output.len() - offset;
+        for i in 0..match_length {
+            output.push(output[start + i]);
+        }
+    }
+
+    if output.len() == size {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn write_sequence(output: &mut Vec<u8>, literals: &[u8], offset: usize, length: usize) {
+    let match_length = length - MIN_MATCH;
+    write_token_and_literals(output, literals, match_length.min(15) as u8);
+    output.extend_from_slice(&(offset as u16).to_le_bytes());
+    if match_length >= 15 {
+        write_length(output, match_length - 15);
+    }
+}
+
+fn write_token_and_literals(output: &mut Vec<u8>, literals: &[u8], match_nibble: u8) {
+    output.push(((literals.len().min(15) as u8) << 4) | match_nibble);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+fn read_length(input: &[u8], pos: &mut usize, nibble: usize) -> Option<usize> {
+    let mut length = nibble;
+    if nibble == 15 {
+        loop {
+            let byte = *input.get(*pos)?;
+            *pos += 1;
+            length = length.checked_add(byte as usize)?;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Some(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let compressed = compress(input);
+        assert_eq!(decompress(&compressed).as_deref(), Some(input));
+        compressed
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"abcdabcdabcdabcd");
+        round_trip(&[7u8; 10_000]);
+
+        let random: Vec<u8> = (0..10_000).map(|_| rand::random::<u8>()).collect();
+        round_trip(&random);
+
+        let json: Vec<u8> = (0..500)
+            .flat_map(|i| format!("{{\"id\": {}, \"name\": \"user {}\"}},", i, i).into_bytes())
+            .collect();
+        let compressed = round_trip(&json);
+        assert!(compressed.len() * 3 < json.len(), "{}", compressed.len());
+    }
+
+    #[test]
+    fn test_decompress_format() {
+        // "abcd", a match of 8 bytes at offset 4 and the last literals "xyzab".
+        let compressed = [
+            17, 0, 0, 0, 0x44, b'a', b'b', b'c', b'd', 4, 0, 0x50, b'x', b'y', b'z', b'a', b'b',
+        ];
+        assert_eq!(
+            decompress(&compressed).as_deref(),
+            Some(&b"abcdabcdabcdxyzab"[..])
+        );
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        let compressed = compress(b"abcdabcdabcdabcdabcdabcd");
+        assert_eq!(decompress(&compressed[..compressed.len() - 1]), None);
+        assert_eq!(decompress(&compressed[..3]), None);
+
+        // Offset 0.
+        assert_eq!(
+            decompress(&[8, 0, 0, 0, 0x40, b'a', b'b', b'c', b'd', 0, 0, 0x00]),
+            None
+        );
+        // Bigger than the declared size.
+        assert_eq!(decompress(&[1, 0, 0, 0, 0x20, b'a', b'b']), None);
+    }
+}
@@ -0,0 +1,278 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufWriter};
+
+use super::levels::NUM_LEVELS;
+use super::sync_dir;
+
+// The manifest is the log of changes made to the set of sstables. Every flush and compaction
+// appends one line with the tables it added and removed, so replaying it gives the live tables,
+// their levels, the next file number and the last sequence number. It is the only source of
+// truth when the tree is opened: files in the directory that it doesn't list are leftovers and
+// are removed.
+//
+// Lines are made of space separated records:
+//  - "next_file <number>": number of the next file to create.
+//  - "last_sequence <number>": sequence number of the newest write in the tables.
+//  - "add <level> <number>": the table with that number is added to the level. Tables added to
+//    level 0 go after the existing ones, except when the same line removes level 0 tables: then
+//    they take the place of the first one, as level 0 is ordered from oldest to newest.
+//  - "remove <number>": the table with that number is no longer used.
+//
+// A line is written with a single write and synced, so a change is either complete or it is the
+// last line and has no newline, in which case it is ignored.
+
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+// One line of the manifest.
+#[derive(Debug, Default, PartialEq)]
+pub struct VersionEdit {
+    pub added: Vec<(usize, u64)>,
+    pub removed: Vec<u64>,
+    pub next_file_number: u64,
+    pub last_sequence: u64,
+}
+
+// Result of replaying the manifest.
+#[derive(Debug, Default, PartialEq)]
+pub struct ManifestState {
+    // Level and number of the live tables. Level 0 tables are ordered from oldest to newest.
+    pub tables: Vec<(usize, u64)>,
+    pub next_file_number: u64,
+    pub last_sequence: u64,
+}
+
+impl ManifestState {
+    fn apply(&mut self, edit: &VersionEdit) {
+        let (mut added_to_level_0, added_to_other_levels): (Vec<_>, Vec<_>) =
+            edit.added.iter().partition(|(level, _)| *level == 0);
+
+        let mut tables = Vec::with_capacity(self.tables.len() + edit.added.len());
+        for (level, number) in self.tables.drain(..) {
+            if !edit.removed.contains(&number) {
+                tables.push((level, number));
+            } else if level == 0 {
+                tables.append(&mut added_to_level_0);
+            }
+        }
+        tables.append(&mut added_to_level_0);
+        tables.extend(added_to_other_levels);
+        self.tables = tables;
+
+        self.next_file_number = self.next_file_number.max(edit.next_file_number);
+        self.last_sequence = self.last_sequence.max(edit.last_sequence);
+    }
+}
+
+pub struct Manifest {
+    file: File,
+    // Size of the complete lines.
+    len: u64,
+}
+
+impl Manifest {
+    // Replays the manifest of the directory. None if there is no manifest.
+    pub fn read(dir: &str) -> io::Result<Option<ManifestState>> {
+        let contents = match fs::read_to_string(manifest_path(dir)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let mut state = ManifestState::default();
+        for line in contents.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(line) => state.apply(&parse_edit(line)?),
+                None => println!("Ignoring incomplete manifest line: {}", line),
+            }
+        }
+        Ok(Some(state))
+    }
+
+    // Starts a new manifest with a single line that adds all the tables of the state. It is
+    // written to a temporary file and renamed, so it replaces the old manifest atomically. A
+    // temporary file left by a crash is overwritten.
+    pub fn create(dir: &str, state: &ManifestState) -> io::Result<Manifest> {
+        let tmp_path = format!("{}.tmp", manifest_path(dir));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(
+            serialize_edit(&VersionEdit {
+                added: state.tables.clone(),
+                removed: Vec::new(),
+                next_file_number: state.next_file_number,
+                last_sequence: state.last_sequence,
+            })
+            .as_bytes(),
+        )?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, manifest_path(dir))?;
+        sync_dir(dir)?;
+
+        let file = OpenOptions::new().append(true).open(manifest_path(dir))?;
+        let len = file.metadata()?.len();
+        Ok(Manifest { file, len })
+    }
+
+    // If it fails, the change may or may not be in the manifest, so it is truncated to the lines
+    // that were complete. Otherwise the next line would be appended to an incomplete one, making
+    // the manifest unreadable.
+    pub fn append(&mut self, edit: &VersionEdit) -> io::Result<()> {
+        let line = serialize_edit(edit);
+        let result = self
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|()| self.file.sync_data());
+        match result {
+            Ok(()) => {
+                self.len += line.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                if let Err(truncate_error) = self.file.set_len(self.len) {
+                    println!("Could not truncate the manifest: {:?}", truncate_error);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+fn manifest_path(dir: &str) -> String {
+    format!("{}/{}", dir, MANIFEST_FILE_NAME)
+}
+
+fn serialize_edit(edit: &VersionEdit) -> String {
+    let mut line = format!(
+        "next_file {} last_sequence {}",
+        edit.next_file_number, edit.last_sequence
+    );
+    for number in &edit.removed {
+        line.push_str(&format!(" remove {}", number));
+    }
+    for (level, number) in &edit.added {
+        line.push_str(&format!(" add {} {}", level, number));
+    }
+    line.push('\n');
+    line
+}
+
+fn parse_edit(line: &str) -> io::Result<VersionEdit> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid manifest line: {}", line),
+        )
+    };
+
+    let mut edit = VersionEdit::default();
+    let mut tokens = line.split(' ');
+    while let Some(name) = tokens.next() {
+        let mut next_number = || -> io::Result<u64> {
+            tokens
+                .next()
+                .and_then(|token| token.parse().ok())
+                .ok_or_else(invalid)
+        };
+
+        match name {
+            "next_file" => edit.next_file_number = next_number()?,
+            "last_sequence" => edit.last_sequence = next_number()?,
+            "remove" => edit.removed.push(next_number()?),
+            "add" => {
+                let level = next_number()? as usize;
+                if level >= NUM_LEVELS {
+                    return Err(invalid());
+                }
+                edit.added.push((level, next_number()?));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(edit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_edits() {
+        let dir = format!("./tmp-{}", rand::random::<u64>());
+        fs::create_dir(&dir).expect("Create tmp folder");
+
+        let mut manifest = Manifest::create(
+            &dir,
+            &ManifestState {
+                tables: vec![(0, 1), (1, 2)],
+                next_file_number: 3,
+                last_sequence: 10,
+            },
+        )
+        .expect("Create manifest");
+        manifest
+            .append(&VersionEdit {
+                added: vec![(1, 3), (1, 4)],
+                removed: vec![1, 2],
+                next_file_number: 5,
+                last_sequence: 10,
+            })
+            .expect("Append edit");
+        manifest
+            .append(&VersionEdit {
+                added: vec![(0, 5)],
+                removed: vec![],
+                next_file_number: 6,
+                last_sequence: 20,
+            })
+            .expect("Append edit");
+        // A line that was being written when the process stopped.
+        manifest
+            .file
+            .write_all(b"next_file 9 last_sequence 30 add 0 8")
+            .expect("Write incomplete line");
+
+        assert_eq!(
+            Manifest::read(&dir).expect("Read manifest"),
+            Some(ManifestState {
+                tables: vec![(1, 3), (1, 4), (0, 5)],
+                next_file_number: 6,
+                last_sequence: 20,
+            })
+        );
+
+        fs::remove_dir_all(dir).expect("Remove tmp folder");
+    }
+
+    #[test]
+    fn test_level_0_tables_keep_their_order() {
+        let mut state = ManifestState {
+            tables: vec![(0, 1), (1, 2), (0, 3), (0, 4), (0, 5)],
+            next_file_number: 6,
+            last_sequence: 10,
+        };
+
+        // The output of merging tables 3 and 4 goes between tables 1 and 5.
+        state.apply(&VersionEdit {
+            added: vec![(0, 6), (1, 7)],
+            removed: vec![3, 4],
+            next_file_number: 8,
+            last_sequence: 10,
+        });
+        assert_eq!(state.tables, vec![(0, 1), (1, 2), (0, 6), (0, 5), (1, 7)]);
+
+        // Without removed level 0 tables, new ones are the newest.
+        state.apply(&VersionEdit {
+            added: vec![(0, 8)],
+            removed: vec![2],
+            next_file_number: 9,
+            last_sequence: 20,
+        });
+        assert_eq!(state.tables, vec![(0, 1), (0, 6), (0, 5), (1, 7), (0, 8)]);
+    }
+
+    #[test]
+    fn test_read_missing_manifest() {
+        assert_eq!(Manifest::read("./does-not-exist").unwrap(), None);
+    }
+}
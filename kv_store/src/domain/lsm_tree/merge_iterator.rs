@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+use std::io;
+
+use crate::domain::Value;
+
+// Key, sequence number and value of a version of a key.
+pub type Entry = (Vec<u8>, u64, Value);
+pub type EntryIterator = Box<dyn Iterator<Item = io::Result<Entry>>>;
+
+// Merges iterators of entries sorted by key, and from newest to oldest version for the same key,
+// into a single iterator with the same order. Every version is returned, the filters below pick
+// the ones needed.
+//
+// Entries of legacy sstables all have sequence number 0. When more than one source has the same
+// version, the one of the last source is returned first, so sources must be ordered from oldest
+// to newest.
+pub struct MergingIterator {
+    sources: Vec<EntryIterator>,
+    // Next entry of each source, in the same order as sources. None when the source is finished.
+    current_entries: Vec<Option<io::Result<Entry>>>,
+}
+
+impl MergingIterator {
+    pub fn new(mut sources: Vec<EntryIterator>) -> Self {
+        let current_entries = sources.iter_mut().map(|source| source.next()).collect();
+        MergingIterator {
+            sources,
+            current_entries,
+        }
+    }
+}
+
+impl Iterator for MergingIterator {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Errors are returned as soon as they are found, and the source is skipped past them.
+        if let Some(index) = self
+            .current_entries
+            .iter()
+            .position(|entry| matches!(entry, Some(Err(_))))
+        {
+            let error = self.current_entries[index].take();
+            self.current_entries[index] = self.sources[index].next();
+            return error;
+        }
+
+        // The first entry is the one with the lowest key and the highest sequence number. Ties
+        // go to the newest source, which is the last of them. If there is none all sources are
+        // finished.
+        let mut first: Option<(usize, &Vec<u8>, u64)> = None;
+        for (i, entry) in self.current_entries.iter().enumerate() {
+            if let Some(Ok((key, sequence, _))) = entry {
+                let goes_first = match first {
+                    None => true,
+                    Some((_, first_key, first_sequence)) => match key.cmp(first_key) {
+                        Ordering::Less => true,
+                        Ordering::Equal => *sequence >= first_sequence,
+                        Ordering::Greater => false,
+                    },
+                };
+                if goes_first {
+                    first = Some((i, key, *sequence));
+                }
+            }
+        }
+
+        let (index, _, _) = first?;
+        let entry = self.current_entries[index].take();
+        self.current_entries[index] = self.sources[index].next();
+        entry
+    }
+}
+
+// Filter for entries in the order of MergingIterator. It keeps the newest version of each key
+// written at or before `sequence`, which is what a read at that sequence number sees.
+pub fn visible_at(sequence: u64) -> impl FnMut(&[u8], u64) -> bool {
+    let mut last_key: Option<Vec<u8>> = None;
+    move |key, entry_sequence| {
+        if entry_sequence > sequence || last_key.as_deref() == Some(key) {
+            return false;
+        }
+        last_key = Some(key.to_vec());
+        true
+    }
+}
+
+// Filter for entries in the order of MergingIterator that drops the versions no read can see:
+// the ones older than a version that is visible to the oldest snapshot. `oldest_snapshot` is the
+// sequence number of that snapshot, or u64::MAX if there are none, which keeps only the newest
+// version of each key.
+pub fn live_versions(oldest_snapshot: u64) -> impl FnMut(&[u8], u64) -> bool {
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut last_sequence = 0;
+    move |key, sequence| {
+        let live = if last_key.as_deref() == Some(key) {
+            last_sequence > oldest_snapshot
+        } else {
+            last_key = Some(key.to_vec());
+            true
+        };
+        last_sequence = sequence;
+        live
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(entries: Vec<(&str, u64, &str)>) -> EntryIterator {
+        let entries: Vec<io::Result<Entry>> = entries
+            .into_iter()
+            .map(|(k, sequence, v)| {
+                Ok((
+                    k.as_bytes().to_vec(),
+                    sequence,
+                    Value::Data(v.as_bytes().to_vec()),
+                ))
+            })
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn collect<F>(iterator: MergingIterator, mut filter: F) -> Vec<(String, String)>
+    where
+        F: FnMut(&[u8], u64) -> bool,
+    {
+        iterator
+            .map(|entry| entry.expect("No errors"))
+            .filter(|(k, sequence, _)| filter(k, *sequence))
+            .map(|(k, _, v)| {
+                let v = v.into_data(0).expect("No tombstones");
+                (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap())
+            })
+            .collect()
+    }
+
+    fn pairs(pairs: Vec<(&str, &str)>) -> Vec<(String, String)> {
+        pairs
+            .into_iter()
+            .map(|(k, v)| (String::from(k), String::from(v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_newest_wins() {
+        // Entries without sequence number, which are ordered by their source.
+        let iterator = MergingIterator::new(vec![
+            source(vec![("a", 0, "old"), ("c", 0, "old"), ("e", 0, "old")]),
+            source(vec![]),
+            source(vec![("b", 0, "new"), ("c", 0, "new")]),
+            source(vec![("c", 0, "newest"), ("f", 0, "newest")]),
+        ]);
+
+        assert_eq!(
+            collect(iterator, visible_at(u64::MAX)),
+            pairs(vec![
+                ("a", "old"),
+                ("b", "new"),
+                ("c", "newest"),
+                ("e", "old"),
+                ("f", "newest"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_merge_versions() {
+        let sources = || {
+            MergingIterator::new(vec![
+                source(vec![("a", 1, "a1"), ("b", 3, "b3"), ("c", 2, "c2")]),
+                source(vec![("a", 6, "a6"), ("a", 4, "a4"), ("c", 5, "c5")]),
+            ])
+        };
+
+        assert_eq!(
+            collect(sources(), |_, _| true),
+            pairs(vec![
+                ("a", "a6"),
+                ("a", "a4"),
+                ("a", "a1"),
+                ("b", "b3"),
+                ("c", "c5"),
+                ("c", "c2"),
+            ])
+        );
+        assert_eq!(
+            collect(sources(), visible_at(4)),
+            pairs(vec![("a", "a4"), ("b", "b3"), ("c", "c2")])
+        );
+        assert_eq!(collect(sources(), visible_at(0)), pairs(vec![]));
+
+        // A snapshot at 4 reads a4 and c2, and newer snapshots read a6 and c5.
+        assert_eq!(
+            collect(sources(), live_versions(4)),
+            pairs(vec![
+                ("a", "a6"),
+                ("a", "a4"),
+                ("b", "b3"),
+                ("c", "c5"),
+                ("c", "c2")
+            ])
+        );
+        assert_eq!(
+            collect(sources(), live_versions(u64::MAX)),
+            pairs(vec![("a", "a6"), ("b", "b3"), ("c", "c5")])
+        );
+    }
+
+    #[test]
+    fn test_merge_returns_errors() {
+        let failing: Vec<io::Result<Entry>> = vec![Err(io::Error::other("broken"))];
+        let mut iterator = MergingIterator::new(vec![
+            source(vec![("a", 1, "1")]),
+            Box::new(failing.into_iter()),
+        ]);
+
+        assert!(iterator.next().expect("Has error").is_err());
+        assert!(iterator.next().expect("Has entry").is_ok());
+        assert!(iterator.next().is_none());
+    }
+}
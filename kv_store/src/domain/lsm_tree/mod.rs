@@ -1,352 +1,671 @@
-mod encoding;
-
-use std::cmp::Ordering;
+mod block_cache;
+mod bloom;
+mod compaction_scheduler;
+mod crc32c;
+pub mod encoding;
+mod levels;
+mod lz4;
+mod manifest;
+pub mod merge_iterator;
+mod sstable;
+
+use std::collections::VecDeque;
 use std::fs;
 use std::fs::File;
-use std::io::prelude::*;
-
-use std::panic;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
 
-use std::io::{self, BufReader, BufWriter};
+use std::io::BufWriter;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
 
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
 use std::thread;
-
-use crate::domain::MemTable;
+use std::time::SystemTime;
+
+use crate::domain::options::Options;
+use crate::domain::snapshot::Snapshots;
+use crate::domain::stats::{Stats, StatsCounters};
+use crate::domain::{unix_millis, MemTable, Value};
+use block_cache::BlockCache;
+use compaction_scheduler::CompactionScheduler;
+use levels::{Compaction, Levels, NUM_LEVELS};
+use merge_iterator::{live_versions, visible_at, EntryIterator, MergingIterator};
+use sstable::{SSTable, SSTableWriter};
 
 #[cfg(test)]
 mod test;
 
-#[derive(Debug)]
-struct SSTable {
-    path: String,
-}
-
-const BUFREADER_CAPACITY: usize = 20 * 1024 * 2014;
-const MAX_SSTABLES: usize = 8;
-
-impl SSTable {
-    fn get_reader(&self) -> io::Result<BufReader<File>> {
-        let file = File::open(&self.path)?;
-        Ok(BufReader::with_capacity(BUFREADER_CAPACITY, file))
-    }
-
-    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
-        let mut reader = self.get_reader()?;
-        let result = encoding::find_value(&mut reader, key);
-        match result {
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
-            a => a,
-        }
-    }
+const SSTABLE_EXTENSION: &str = "sstable";
+// Compactions split their output in tables of about this size, so later compactions can pick a
+// small part of a level.
+const TARGET_SSTABLE_SIZE: u64 = 2 * 1024 * 1024;
 
-    fn delete_file(&self) -> io::Result<()> {
-        fs::remove_file(&self.path)?;
-        Ok(())
-    }
+pub struct LSMTree<T: MemTable> {
+    flush_queue: Arc<FlushQueue<T>>,
+    storage: Storage,
+    // Both are None only while the tree is dropped.
+    flush_sender: Option<mpsc::Sender<FlushJob<T>>>,
+    flush_worker: Option<thread::JoinHandle<()>>,
+    // Shared with the flush worker, which tells it when memtables are saved. It stops when both
+    // are dropped.
+    compactions: Arc<CompactionScheduler>,
+    stats: Arc<StatsCounters>,
 }
 
-impl Clone for SSTable {
-    fn clone(&self) -> Self {
-        SSTable {
-            path: self.path.clone(),
-        }
-    }
+// Memtables waiting to be saved, shared with the flush worker, which saves them one at a time in
+// the order they were added.
+struct FlushQueue<T> {
+    // Oldest first. A memtable is removed when its sstable is added to the levels, so its entries
+    // can always be read from one of them.
+    memtables: RwLock<VecDeque<Arc<T>>>,
+    // Memtables sent to the worker that it hasn't finished with.
+    pending: Mutex<usize>,
+    // Notified when a memtable leaves the queue and when the worker finishes with one.
+    changed: Condvar,
+    max_memtables: usize,
 }
-impl PartialEq for SSTable {
-    fn eq(&self, other: &Self) -> bool {
-        self.path == other.path
-    }
+
+struct FlushJob<T> {
+    memtable: Arc<T>,
+    // Write-ahead logs holding the entries of the memtable, removed once it is on disk.
+    wal_paths: Vec<String>,
+    // Requests a full compaction once the memtable is saved.
+    compact_all: bool,
 }
 
-pub struct LSMTree<T: MemTable> {
+// Everything the background threads need to write sstables and change the levels.
+#[derive(Clone)]
+struct Storage {
     sstable_dir: String,
+    levels: Arc<RwLock<Levels>>,
+
+    // Number used in the filename of the next sstable. It is saved in the manifest, so names are
+    // never reused and the order of level 0 tables is the order of their names.
+    next_file_number: Arc<AtomicU64>,
+
+    // Options used to write and read the sstables. Changing the size of the bloom filters only
+    // affects new sstables, existing ones keep the filter they were written with.
+    options: Options,
+
+    // Shared by all the sstables.
+    cache: Arc<BlockCache>,
 
-    // Secuential number indicating how many sstables we ATTEPTED to save. As writing to the disk
-    // can fail, this number might be bigger than the actual number of tables on disk. As it is
-    // used for creating the filename of a new sstable file, there can be missing numbers in the
-    // list of files. As we only care about the relative order (and it is maintained) this is no
-    // problem.
-    sstable_current_index: u32,
-
-    tmp_memtable: Arc<RwLock<Option<T>>>,
-    // List of SSTables saved on disk, order should be the same as order of filenames
-    sstables: Arc<RwLock<Vec<SSTable>>>,
-    save_tmp_table_handle: Option<thread::JoinHandle<()>>,
+    // Flushes and compactions keep the versions that live snapshots can read.
+    snapshots: Arc<Snapshots>,
+
+    // First error of the background threads, if any.
+    background_error: Arc<Mutex<Option<String>>>,
 }
 
 impl<T: MemTable> LSMTree<T> {
-    pub fn new(dir: &str) -> Self {
+    pub fn open(dir: &str, options: &Options) -> io::Result<Self> {
         let dir = String::from(dir);
 
-        let mut ret = LSMTree {
-            sstables: Arc::new(RwLock::new(Vec::new())),
-            sstable_dir: dir.clone(),
-            sstable_current_index: 0,
-            tmp_memtable: Arc::new(RwLock::new(None)),
-            save_tmp_table_handle: None,
-        };
-
         if let Err(error) = fs::create_dir(&dir) {
             match error.kind() {
                 std::io::ErrorKind::AlreadyExists => {
-                    let mut paths: Vec<String> = fs::read_dir(&dir)
-                        .unwrap()
-                        .map(|path| path.unwrap().path().to_str().unwrap().to_owned())
-                        .collect();
-                    paths.sort();
-
                     println!("sstable folder already exists, loading data");
-                    {
-                        let mut sstables = ret.sstables.write().unwrap();
-                        for path in paths {
-                            println!("Found sstable: {}", path);
-                            sstables.push(SSTable { path });
-                        }
-                        ret.sstable_current_index = sstables.len() as u32;
-                    }
-                    println!("stored data loaded");
                 }
-                _ => panic!(error),
+                _ => return Err(error),
             };
         }
 
-        ret
+        let stats = Arc::new(StatsCounters::default());
+        let cache = Arc::new(BlockCache::new(options.block_cache_size, stats.clone()));
+        let (levels, next_file_number) = Levels::open(&dir, options, &cache)?;
+        println!("stored data loaded");
+
+        let storage = Storage {
+            sstable_dir: dir,
+            levels: Arc::new(RwLock::new(levels)),
+            next_file_number: Arc::new(AtomicU64::new(next_file_number)),
+            options: options.clone(),
+            cache,
+            snapshots: Arc::new(Snapshots::default()),
+            background_error: Arc::new(Mutex::new(None)),
+        };
+        let flush_queue = Arc::new(FlushQueue {
+            memtables: RwLock::new(VecDeque::new()),
+            pending: Mutex::new(0),
+            changed: Condvar::new(),
+            max_memtables: options.max_immutable_memtables,
+        });
+        let compactions = Arc::new(CompactionScheduler::new(storage.clone(), stats.clone()));
+        let (flush_sender, jobs) = mpsc::channel();
+        let flush_worker = flush_worker(
+            storage.clone(),
+            flush_queue.clone(),
+            compactions.clone(),
+            jobs,
+        );
+
+        Ok(LSMTree {
+            flush_queue,
+            storage,
+            flush_sender: Some(flush_sender),
+            flush_worker: Some(flush_worker),
+            compactions,
+            stats,
+        })
     }
 
-    fn len(&self) -> usize {
-        let sstables = self.sstables.read().unwrap();
-        sstables.len()
+    pub fn stats(&self) -> Stats {
+        self.stats.snapshot()
     }
 
-    fn generate_new_sstable_path(&mut self) -> String {
-        let ret = format!(
-            "{}/{:08}.sstable",
-            self.sstable_dir, self.sstable_current_index
-        );
-        self.sstable_current_index += 1;
-        ret
+    pub fn snapshots(&self) -> &Arc<Snapshots> {
+        &self.storage.snapshots
     }
 
-    pub fn save_memtable(&mut self, memtable: T) {
-        self._save_memtable(memtable, self.len() > MAX_SSTABLES);
+    // Sequence number of the newest write saved in the sstables.
+    pub fn last_sequence(&self) -> u64 {
+        self.storage.levels.read().unwrap().last_sequence()
     }
 
-    fn _save_memtable(&mut self, memtable: T, merge: bool) {
-        self.wait_for_threads();
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        let levels = self.storage.levels.read().unwrap();
+        levels.len()
+    }
 
-        let memtable_lock = Arc::new(RwLock::new(Some(memtable)));
-        self.tmp_memtable = memtable_lock.clone();
+    // Adds the memtable to the queue of memtables that the flush worker saves as sstables. When
+    // the queue is full it first waits until the oldest memtable is saved. The compactions needed
+    // afterwards are run by the compaction scheduler. Callers must not add memtables
+    // concurrently.
+    //
+    // Errors of the worker are available in `background_error`. After an error no more memtables
+    // are saved, as the one that failed must go to disk before newer ones. They stay in the
+    // queue, so they can still be read, and their write-ahead logs are replayed on the next
+    // start.
+    pub fn save_memtable(&self, memtable: T, wal_paths: Vec<String>) {
+        self._save_memtable(memtable, wal_paths, false);
+    }
 
-        let path = self.generate_new_sstable_path();
-        self.save_tmp_table_handle = Some(save_memtable_thread(
-            path,
-            self.sstables.clone(),
-            memtable_lock,
-            merge,
-        ));
+    // With `compact_all` every table is merged into a single level after saving the memtable.
+    fn _save_memtable(&self, memtable: T, wal_paths: Vec<String>, compact_all: bool) {
+        self.wait_for_room();
+
+        let memtable = Arc::new(memtable);
+        self.flush_queue
+            .memtables
+            .write()
+            .unwrap()
+            .push_back(memtable.clone());
+        *self.flush_queue.pending.lock().unwrap() += 1;
+        let job = FlushJob {
+            memtable,
+            wal_paths,
+            compact_all,
+        };
+        self.flush_sender
+            .as_ref()
+            .expect("The worker runs until the tree is dropped")
+            .send(job)
+            .expect("The worker stops only when the sender is dropped");
     }
 
-    fn wait_for_threads(&mut self) {
-        let save_handle_opt = std::mem::replace(&mut self.save_tmp_table_handle, None);
+    // Waits until the queue has room for another memtable. It returns right away after a
+    // background error, as the queue won't be drained anymore.
+    pub fn wait_for_room(&self) {
+        let queue = &self.flush_queue;
+        let mut pending = queue.pending.lock().unwrap();
+        let mut stalled = false;
+        while queue.memtables.read().unwrap().len() >= queue.max_memtables
+            && self.background_error().is_none()
+        {
+            if !stalled {
+                StatsCounters::increment(&self.stats.write_stalls);
+                stalled = true;
+            }
+            pending = queue.changed.wait(pending).unwrap();
+        }
+    }
 
-        if let Some(handle) = save_handle_opt {
-            let result = handle.join();
-            if let Err(e) = result {
-                println!("Error in save memtable thread: {:?}", e);
+    // Waits until the worker has finished with every memtable in the queue, and then until the
+    // compactions they need are done. While compactions are paused it doesn't wait for them.
+    pub fn wait_for_threads(&self) {
+        {
+            let mut pending = self.flush_queue.pending.lock().unwrap();
+            while *pending > 0 {
+                pending = self.flush_queue.changed.wait(pending).unwrap();
             }
         }
+        self.compactions.wait_until_idle();
+    }
+
+    // Running compactions finish, but no new ones start until `resume_compactions`.
+    pub fn pause_compactions(&self) {
+        self.compactions.pause();
+    }
+
+    pub fn resume_compactions(&self) {
+        self.compactions.resume();
+    }
+
+    pub fn background_error(&self) -> Option<String> {
+        self.storage.background_error.lock().unwrap().clone()
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        let memtable_result = {
-            let memtable = self.tmp_memtable.read().unwrap();
-            match &*memtable {
-                None => None,
-                Some(memtable) => memtable.get(&key.to_vec()).cloned(),
+    // Newest version of the key written at or before `sequence`. Fails if a table is corrupted,
+    // instead of returning None or an older value.
+    pub fn get(&self, key: &[u8], sequence: u64) -> io::Result<Option<Value>> {
+        // Newer memtables have newer versions, so they are checked first.
+        let memtables = self.flush_queue.memtables();
+        for memtable in memtables.iter().rev() {
+            if let Some(value) = memtable.get(key, sequence) {
+                return Ok(Some(value.clone()));
             }
-        };
+        }
 
-        if let Some(result) = memtable_result {
-            return Some(result);
-        };
+        // Versions in newer tables are always newer, so the first table with a version visible
+        // at the sequence number has the right one.
+        let levels = self.storage.levels.read().unwrap();
 
-        let sstables = self.sstables.read().unwrap();
+        for sstable in levels.tables_for_key(key) {
+            StatsCounters::increment(&self.stats.bloom_filter_checks);
+            if !sstable.may_contain(key) {
+                StatsCounters::increment(&self.stats.bloom_filter_useful);
+                continue;
+            }
+
+            if let Some(value) = sstable.get(key, sequence)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    // Iterates the entries with keys in the range, in key order, including tombstones. Only the
+    // newest version of each key written at or before `sequence` is returned.
+    //
+    // The files are opened before returning, so the iterator keeps working if the sstables are
+    // compacted in the meantime.
+    pub fn range(&self, range: &(Bound<Vec<u8>>, Bound<Vec<u8>>), sequence: u64) -> EntryIterator {
+        let start: &[u8] = match &range.0 {
+            Bound::Included(start) | Bound::Excluded(start) => start,
+            Bound::Unbounded => &[],
+        };
+        let end: Option<&[u8]> = match &range.1 {
+            Bound::Included(end) | Bound::Excluded(end) => Some(end),
+            Bound::Unbounded => None,
+        };
+        // Only the tables that can have keys in the range are read, as each iterator opens its
+        // file.
+        let in_range = |sstable: &&SSTable| {
+            sstable
+                .key_range()
+                .is_some_and(|(smallest_key, largest_key)| {
+                    largest_key >= start && end.is_none_or(|end| smallest_key <= end)
+                })
+        };
+        let iter_from_start = |sstable: &SSTable| -> EntryIterator {
+            match sstable.iter_from(start) {
+                Ok(iterator) => Box::new(iterator),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            }
+        };
 
-        for sstable in (&*sstables).iter().rev() {
-            if let Some(value) = sstable.get(key).unwrap() {
-                return Some(value);
+        // The memtables are read before the levels. A memtable leaves the queue only after its
+        // sstable is in the levels, so its entries are in one of them even if it is saved in
+        // between. They are newer than every sstable.
+        let memtable_sources: Vec<EntryIterator> = self
+            .flush_queue
+            .memtables()
+            .into_iter()
+            .map(|memtable| -> EntryIterator {
+                let memtable_entries: Vec<_> = memtable
+                    .sorted_entries()
+                    .into_iter()
+                    .filter(|(key, _, _)| range.contains(*key))
+                    .map(|(key, sequence, value)| Ok((key.clone(), sequence, value.clone())))
+                    .collect();
+                Box::new(memtable_entries.into_iter())
+            })
+            .collect();
+
+        let mut sources: Vec<EntryIterator> = Vec::new();
+        {
+            let levels = self.storage.levels.read().unwrap();
+            // Deeper levels have older data. The tables of a level other than 0 don't overlap,
+            // so they can be read one after the other as a single source.
+            for level in (1..NUM_LEVELS).rev() {
+                let tables: Vec<EntryIterator> = levels
+                    .level(level)
+                    .iter()
+                    .filter(in_range)
+                    .map(iter_from_start)
+                    .collect();
+                sources.push(Box::new(tables.into_iter().flatten()));
             }
+            sources.extend(levels.level(0).iter().filter(in_range).map(iter_from_start));
         }
-        None
+        sources.extend(memtable_sources);
+
+        let start_bound = range.0.clone();
+        let end_bound = range.1.clone();
+        let mut visible = visible_at(sequence);
+        Box::new(
+            MergingIterator::new(sources)
+                .filter(move |entry| match entry {
+                    Ok((key, entry_sequence, _)) => visible(key, *entry_sequence),
+                    Err(_) => true,
+                })
+                .skip_while(move |entry| match entry {
+                    Ok((key, _, _)) => !(start_bound.clone(), Bound::Unbounded).contains(key),
+                    Err(_) => false,
+                })
+                .take_while(move |entry| match entry {
+                    Ok((key, _, _)) => (Bound::Unbounded, end_bound.clone()).contains(key),
+                    Err(_) => true,
+                }),
+        )
     }
 }
 
 impl<T: MemTable> Drop for LSMTree<T> {
+    // The worker saves the memtables left in the queue and stops once the sender is dropped. The
+    // compaction scheduler stops after it, when the last reference to it is dropped, letting the
+    // running compactions finish.
     fn drop(&mut self) {
-        self.wait_for_threads();
+        self.flush_sender.take();
+        if let Some(worker) = self.flush_worker.take() {
+            if let Err(e) = worker.join() {
+                println!("Error in flush worker: {:?}", e);
+            }
+        }
     }
 }
 
-fn save_memtable_thread<T: MemTable + Send + Sync + 'static>(
-    path: String,
-    sstables: Arc<RwLock<Vec<SSTable>>>,
-    memtable_lock: Arc<RwLock<Option<T>>>,
-    merge_all: bool,
-) -> thread::JoinHandle<()> {
-    thread::spawn(move || {
-        let serialized = {
-            let memtable = memtable_lock.read().unwrap();
-            let values = match &*memtable {
-                Some(memtable) => memtable.sorted_entries(),
-                None => panic!("Should have memtable to save"),
-            };
-            encoding::serialize_values(&values)
-        };
+impl<T> FlushQueue<T> {
+    // Memtables in the queue, oldest first. They are cloned, so reading them doesn't block the
+    // worker.
+    fn memtables(&self) -> Vec<Arc<T>> {
+        self.memtables.read().unwrap().iter().cloned().collect()
+    }
 
-        let mut file = match File::create(&path) {
-            Err(e) => {
-                println!("{:?}", e);
-                panic!(e);
-            }
-            Ok(file) => file,
-        };
+    // The lock is taken so waiters can't miss the notification between checking their condition
+    // and waiting.
+    fn notify(&self) {
+        let _pending = self.pending.lock().unwrap();
+        self.changed.notify_all();
+    }
 
-        if let Err(e) = file.write_all(&serialized[..]) {
-            panic!(e)
-        }
+    fn finish_job(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        *pending -= 1;
+        self.changed.notify_all();
+    }
+}
 
-        {
-            // It's important to write both sstables and tmp_memtable at the same time, so there no
-            // point in time where the memtable is dropped and the corresponding sstable is not in
-            // the sstables list.
-            let mut sstables = sstables.write().unwrap();
-            let mut tmp_memtable = memtable_lock.write().unwrap();
-            sstables.push(SSTable { path: path.clone() });
-            *tmp_memtable = None;
+impl Storage {
+    fn set_background_error(&self, message: String) {
+        let mut background_error = self.background_error.lock().unwrap();
+        if background_error.is_none() {
+            *background_error = Some(message);
         }
+    }
 
-        if merge_all {
-            merge_sstables(sstables, path);
-        }
-    })
-}
+    fn new_sstable_path(&self) -> String {
+        let number = self.next_file_number.fetch_add(1, Ordering::SeqCst);
+        sstable_path(&self.sstable_dir, number)
+    }
 
-fn merge_sstables(sstables_lock: Arc<RwLock<Vec<SSTable>>>, merged_path: String) {
-    let sstables = sstables_lock.read().unwrap();
-    if sstables.len() < 2 {
-        panic!("Cannot merge less than 2 tables");
+    // Versions that no snapshot can read are dropped when tables are written. Snapshots taken
+    // later have a bigger sequence number than anything being written, so they only need the
+    // newest versions.
+    fn oldest_snapshot(&self) -> u64 {
+        self.snapshots.oldest().unwrap_or(u64::MAX)
     }
+}
 
-    let tmp_merged_path = format!("{}.tmp", merged_path);
-    let merged_file = File::create(&tmp_merged_path).expect("Should be able to create file");
-    let mut writer = BufWriter::new(merged_file);
+// Syncing a file doesn't sync its entry in the directory, so new files are only durable after
+// syncing the directory. It's done before the manifest references them.
+fn sync_dir(dir: &str) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
 
-    let n_tables = sstables.len();
-    let mut current_key_vec: Vec<Option<Vec<u8>>> = Vec::new();
-    current_key_vec.resize(n_tables, None);
+fn sstable_path(dir: &str, number: u64) -> String {
+    format!("{}/{:08}.{}", dir, number, SSTABLE_EXTENSION)
+}
 
-    let mut reader_vec: Vec<BufReader<File>> = sstables
-        .iter()
-        .map(|sstable| sstable.get_reader().unwrap())
-        .collect();
+// Number in the name of an sstable file, None if the name is not a number.
+fn file_number(path: &str) -> Option<u64> {
+    Path::new(path).file_stem()?.to_str()?.parse().ok()
+}
 
-    let mut buffer: Vec<u8> = Vec::new();
+fn flush_worker<T: MemTable>(
+    storage: Storage,
+    queue: Arc<FlushQueue<T>>,
+    compactions: Arc<CompactionScheduler>,
+    jobs: mpsc::Receiver<FlushJob<T>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for job in jobs {
+            if storage.background_error.lock().unwrap().is_none() {
+                // A panic is turned into a background error, so the worker keeps finishing
+                // jobs and nobody waits for it forever.
+                let result =
+                    panic::catch_unwind(AssertUnwindSafe(|| save_memtable(&storage, &queue, &job)));
+                match result {
+                    Ok(Ok(())) => {
+                        // Requested before finishing the job, so waiting for the threads also
+                        // waits for the compaction.
+                        if job.compact_all {
+                            compactions.request_full_compaction();
+                        }
+                        compactions.memtable_saved();
+                    }
+                    Ok(Err(e)) => {
+                        println!("Error in flush worker: {:?}", e);
+                        storage.set_background_error(e.to_string());
+                    }
+                    Err(_) => storage.set_background_error(String::from("Flush worker panicked")),
+                }
+            }
+            queue.finish_job();
+        }
+    })
+}
 
-    // Read initial values
-    for i in 0..n_tables {
-        let key_size_opt = encoding::read_next_datum(&mut reader_vec[i], &mut buffer);
-        current_key_vec[i] = match key_size_opt {
-            Ok(key_size) => Some(buffer[..key_size].to_vec()),
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
-            Err(e) => panic!(e),
+// If saving the memtable fails it stays in the queue, so its entries can still be read, and its
+// write-ahead logs are kept so they are replayed on the next start. The sstable is removed, as
+// nothing references it.
+fn save_memtable<T: MemTable>(
+    storage: &Storage,
+    queue: &FlushQueue<T>,
+    job: &FlushJob<T>,
+) -> io::Result<()> {
+    // The write-ahead logs are removed below, so the sstable must really be on disk.
+    // SSTable::create syncs the file before returning. Empty memtables are not saved.
+    let (sstable, last_sequence) = {
+        let values = job.memtable.sorted_entries();
+        let last_sequence = values.iter().map(|(_, sequence, _)| *sequence).max();
+
+        let mut live = live_versions(storage.oldest_snapshot());
+        let values: Vec<_> = values
+            .into_iter()
+            .filter(|(key, sequence, _)| live(key, *sequence))
+            .collect();
+
+        if values.is_empty() {
+            (None, last_sequence)
+        } else {
+            let path = storage.new_sstable_path();
+            let sstable = SSTable::create(path.clone(), values, &storage.options, &storage.cache)
+                .and_then(|sstable| sync_dir(&storage.sstable_dir).map(|()| sstable))
+                .map_err(|e| remove_new_files(e, &[path]))?;
+            (Some(sstable), last_sequence)
+        }
+    };
+
+    {
+        // It's important to change both the levels and the queue at the same time, so there is
+        // no point in time where the memtable is gone and the corresponding sstable is not in
+        // the levels.
+        let mut levels = storage.levels.write().unwrap();
+        let mut memtables = queue.memtables.write().unwrap();
+        if let Some(sstable) = sstable {
+            let next_file_number = storage.next_file_number.load(Ordering::SeqCst);
+            let last_sequence = last_sequence.expect("Tables are only saved with entries");
+            let path = sstable.path.clone();
+            levels
+                .add_flushed_table(sstable, next_file_number, last_sequence)
+                .map_err(|e| remove_new_files(e, &[path]))?;
         }
+        let memtable = memtables.pop_front();
+        assert!(
+            memtable.is_some_and(|memtable| Arc::ptr_eq(&memtable, &job.memtable)),
+            "Memtables are saved in order"
+        );
     }
+    queue.notify();
 
-    loop {
-        // FIND INDEXES WITH LOWER KEY
-        let first_some_index = current_key_vec.iter().position(|key_opt| key_opt.is_some());
+    for wal_path in &job.wal_paths {
+        if let Err(e) = fs::remove_file(wal_path) {
+            println!("Could not remove write-ahead log {}: {:?}", wal_path, e);
+        }
+    }
+    Ok(())
+}
 
-        if first_some_index.is_none() {
-            // All keys are None, we finished merging
-            break;
+// A compaction is applied in steps, so a crash at any point leaves a usable tree:
+//  1. The outputs are written and synced, with the directory. Nothing references them yet.
+//  2. A single line of the manifest replaces the inputs with the outputs.
+//  3. The inputs are removed. If this doesn't happen, they are removed when the tree is opened
+//     again, like outputs of compactions that didn't reach step 2.
+//
+// If the compaction fails before step 2, the levels don't change and the outputs are removed.
+fn run_compaction(storage: &Storage, compaction: Compaction) -> io::Result<CompactionStats> {
+    let mut output_paths = Vec::new();
+    let (outputs, stats) = if compaction.trivial_move {
+        (compaction.inputs.clone(), CompactionStats::default())
+    } else {
+        write_compaction_outputs(storage, &compaction, &mut output_paths)
+            .and_then(|outputs| sync_dir(&storage.sstable_dir).map(|()| outputs))
+            .map_err(|e| remove_new_files(e, &output_paths))?
+    };
+
+    let unused_tables = {
+        let mut levels = storage.levels.write().unwrap();
+        let next_file_number = storage.next_file_number.load(Ordering::SeqCst);
+        levels
+            .apply(&compaction, outputs, next_file_number)
+            .map_err(|e| remove_new_files(e, &output_paths))?
+    };
+
+    // The compaction is already done, so failing to remove an input is not an error.
+    for sstable in unused_tables {
+        if let Err(e) = sstable.delete_file() {
+            println!("Could not remove unused sstable {}: {:?}", sstable.path, e);
         }
+    }
+    Ok(stats)
+}
 
-        let mut lowest_key: &Vec<u8> = match current_key_vec
-            [first_some_index.expect("We already checked that this is not None")]
-        {
-            Some(ref v) => v,
+// Removes files created by a flush or compaction that failed with `error`, which is returned.
+fn remove_new_files(error: io::Error, paths: &[String]) -> io::Error {
+    for path in paths {
+        if let Err(e) = fs::remove_file(path) {
+            println!("Could not remove {}: {:?}", path, e);
+        }
+    }
+    error
+}
 
-            // We already checked that first_some_index is an index of a Some
-            None => panic!("Should not get here"),
-        };
+// Entries that a compaction didn't copy to its outputs.
+#[derive(Debug, Default, PartialEq)]
+struct CompactionStats {
+    // Tombstones, including expired values, with no older versions left to hide.
+    dropped_tombstones: u64,
+    // Versions replaced by a newer one that no snapshot can read.
+    dropped_versions: u64,
+}
 
-        let mut lowest_key_indexes: Vec<usize> =
-            vec![first_some_index.expect("We already checked that this is not None")];
+// Merges the inputs, which are ordered from oldest to newest, into new tables of about
+// TARGET_SSTABLE_SIZE. Versions of a key are never split between tables, so each level has at
+// most one table with the key. Outputs into level 0 are not split, as every level 0 table is
+// checked by reads, and splitting them would make level 0 grow instead of shrink. The paths of
+// the files it creates are added to `output_paths`, also when it fails.
+fn write_compaction_outputs(
+    storage: &Storage,
+    compaction: &Compaction,
+    output_paths: &mut Vec<String>,
+) -> io::Result<(Vec<SSTable>, CompactionStats)> {
+    let mut sources: Vec<EntryIterator> = Vec::with_capacity(compaction.inputs.len());
+    for sstable in &compaction.inputs {
+        sources.push(Box::new(sstable.iter()?));
+    }
 
-        for (i, current_key_opt) in current_key_vec
-            .iter()
-            .enumerate()
-            .take(n_tables)
-            .skip(lowest_key_indexes[0] + 1)
-        {
-            if let Some(current_key) = current_key_opt {
-                match current_key.cmp(lowest_key) {
-                    Ordering::Greater => {}
-                    Ordering::Equal => lowest_key_indexes.push(i),
-                    Ordering::Less => {
-                        lowest_key_indexes = vec![i];
-                        lowest_key = current_key;
-                    }
-                }
-            }
+    let mut outputs = Vec::new();
+    let mut current: Option<(String, SSTableWriter<BufWriter<File>>)> = None;
+    let oldest_snapshot = storage.oldest_snapshot();
+    let mut live = live_versions(oldest_snapshot);
+    let now = unix_millis(SystemTime::now());
+    let mut stats = CompactionStats::default();
+
+    let mut entries = MergingIterator::new(sources).peekable();
+    while let Some(entry) = entries.next() {
+        let (key, sequence, mut value) = entry?;
+        if !live(&key, sequence) {
+            stats.dropped_versions += 1;
+            continue;
         }
 
-        // If multiple ones have the same key, use first in the sstables_to_merge reverse order
-        // As current_key_vec has the same order as sstables_to_merge, we just have to take the
-        // last element from the lowest_key_indexes.
-        let persisted_index: usize = *lowest_key_indexes
-            .last()
-            .expect("Should have at least one element");
-
-        let persisted_key = lowest_key;
-        let persisted_value_size: usize =
-            encoding::read_next_datum(&mut reader_vec[persisted_index], &mut buffer)
-                .expect("Every key should have a value");
-        let persisted_value = &buffer[..persisted_value_size];
-
-        // Add key+value of lowest to the merged sstable
-        writer
-            .write_all(&encoding::serialize_entry(persisted_key, persisted_value))
-            .expect("Should be able to write");
-
-        for index in lowest_key_indexes {
-            // Skip all values with the same key, as we already got the one we want
-            // (persisted_index).
-            if index != persisted_index {
-                encoding::read_next_datum(&mut reader_vec[index], &mut buffer)
-                    .expect("Every key should have a value");
-            }
+        // Expired values are read as deleted, so they are replaced by a tombstone, which keeps
+        // older versions hidden without the space of the value.
+        if value.is_expired(now) {
+            value = Value::Tombstone;
+        }
 
-            let key_size_opt = encoding::read_next_datum(&mut reader_vec[index], &mut buffer);
-            current_key_vec[index] = match key_size_opt {
-                Ok(key_size) => Some(buffer[..key_size].to_vec()),
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
-                Err(e) => panic!(e),
+        // Tombstones only hide older versions. The older versions in the compaction are kept
+        // only if a snapshot doesn't see the tombstone, and if there are none and no table
+        // outside the compaction can have them, there is nothing to hide.
+        if value == Value::Tombstone {
+            let has_older_versions = sequence > oldest_snapshot
+                && matches!(entries.peek(), Some(Ok((next_key, _, _))) if *next_key == key);
+            if !has_older_versions && compaction.has_oldest_versions_of(&key) {
+                stats.dropped_tombstones += 1;
+                continue;
             }
         }
-    }
 
-    std::mem::drop(sstables);
-    std::mem::drop(writer);
+        let table_is_full = compaction.output_level > 0
+            && current.as_ref().is_some_and(|(_, writer)| {
+                writer.estimated_size() >= TARGET_SSTABLE_SIZE && writer.last_key() != &key[..]
+            });
+        if table_is_full {
+            let (path, writer) = current.take().expect("Should have a writer");
+            writer.finish_and_sync()?;
+            outputs.push(SSTable::open(
+                path,
+                storage.options.read_buffer_size,
+                &storage.cache,
+            )?);
+        }
 
-    let mut sstables = sstables_lock.write().unwrap();
-    for sst in &*sstables {
-        sst.delete_file().expect("Can delete old sstables");
+        let (_, writer) = match &mut current {
+            Some(current) => current,
+            None => {
+                let path = storage.new_sstable_path();
+                output_paths.push(path.clone());
+                let writer =
+                    SSTableWriter::new(BufWriter::new(File::create(&path)?), &storage.options);
+                current.insert((path, writer))
+            }
+        };
+        writer.add(&key, sequence, &value)?;
+    }
+
+    if let Some((path, writer)) = current {
+        writer.finish_and_sync()?;
+        outputs.push(SSTable::open(
+            path,
+            storage.options.read_buffer_size,
+            &storage.cache,
+        )?);
     }
 
-    fs::rename(&tmp_merged_path, &merged_path).expect("I can move file");
-    *sstables = vec![SSTable { path: merged_path }];
-    // Sorting is needed if a newer table was added while merging old ones.
-    sstables.sort_by(|p1, p2| p1.path.cmp(&p2.path));
+    Ok((outputs, stats))
 }
@@ -0,0 +1,524 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, Cursor, SeekFrom};
+use std::sync::Arc;
+
+use super::block_cache::{Block, BlockCache};
+use super::bloom::{self, BloomFilter};
+use super::encoding::{self, BlockHandle, Footer, FormatVersion};
+use super::merge_iterator::Entry;
+use crate::domain::options::{Compression, Options};
+use crate::domain::Value;
+
+// Size at which a data block is closed. Entries are never split between blocks, and neither are
+// the versions of a key, so blocks can be a bit bigger than this.
+const BLOCK_SIZE: usize = 4 * 1024;
+// Capacity of the buffer of the iterators used by range scans. A scan has an iterator for every
+// table in its range, so they use a smaller buffer than full reads of a table.
+const RANGE_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// An sstable is made of:
+//  - Data blocks: entries with key, sequence number and value, one after the other. They are
+//    sorted by key, and from newest to oldest version for the same key. All the versions of a
+//    key are in the same block.
+//  - Index block: one entry per data block, with the last key of the block as key and the block
+//    handle (offset and size) as value.
+//  - Filter block: bloom filter of all the keys in the table. Empty if it was written with
+//    0 bits per key.
+//  - Footer: the block handles of the index and filter blocks, and a magic number.
+//
+// Every block is followed by its compression type and a CRC32C of its contents, which is checked
+// each time it is read.
+//
+// Files written before this format only contain the entries, without index nor footer. They are
+// still readable, but each lookup has to scan the whole file. They are rewritten in the new
+// format the next time the tables are merged.
+
+#[derive(Debug)]
+struct IndexEntry {
+    last_key: Vec<u8>,
+    handle: BlockHandle,
+}
+
+#[derive(Debug, Clone)]
+pub struct SSTable {
+    pub path: String,
+    // None for legacy files without index.
+    index: Option<Arc<Vec<IndexEntry>>>,
+    filter: Option<Arc<BloomFilter>>,
+    // Size of the part of the file that contains entries.
+    data_size: u64,
+    file_size: u64,
+    // Smallest and largest keys of the table. None if it has no entries.
+    key_range: Option<(Vec<u8>, Vec<u8>)>,
+    version: FormatVersion,
+    // Capacity of the buffer used to read the file sequentially.
+    read_buffer_size: usize,
+    // Data blocks are read through the cache, where the table is identified by this id.
+    cache: Arc<BlockCache>,
+    cache_id: u64,
+}
+
+impl SSTable {
+    pub fn open(
+        path: String,
+        read_buffer_size: usize,
+        cache: &Arc<BlockCache>,
+    ) -> io::Result<SSTable> {
+        let mut file = File::open(&path)?;
+        let file_size = file.metadata()?.len();
+
+        let footer = if file_size >= encoding::FOOTER_SIZE as u64 {
+            let mut footer_bytes = [0u8; encoding::FOOTER_SIZE];
+            file.seek(SeekFrom::End(-(encoding::FOOTER_SIZE as i64)))?;
+            file.read_exact(&mut footer_bytes)?;
+            encoding::deserialize_footer(&footer_bytes)
+        } else {
+            None
+        };
+
+        match footer {
+            None => {
+                let mut sstable = SSTable {
+                    path,
+                    index: None,
+                    filter: None,
+                    data_size: file_size,
+                    file_size,
+                    key_range: None,
+                    version: FormatVersion::Legacy,
+                    read_buffer_size,
+                    cache: cache.clone(),
+                    cache_id: cache.new_table_id(),
+                };
+                // Legacy tables have no index, so the only way to know their key range is
+                // reading them. They are rewritten by the first compaction that includes them.
+                let mut smallest_key = None;
+                let mut largest_key = None;
+                for entry in sstable.iter()? {
+                    let (key, _, _) = entry?;
+                    if smallest_key.is_none() {
+                        smallest_key = Some(key.clone());
+                    }
+                    largest_key = Some(key);
+                }
+                sstable.key_range = smallest_key.zip(largest_key);
+                Ok(sstable)
+            }
+            Some(footer) => {
+                let index_block = read_block(&mut file, &footer.index)?;
+                let index = deserialize_index(&index_block)?;
+                let filter_block = read_block(&mut file, &footer.filter)?;
+                let filter = BloomFilter::deserialize(&filter_block);
+
+                let key_range = match (index.first(), index.last()) {
+                    (Some(first_block), Some(last_block)) => {
+                        let block = read_block(&mut file, &first_block.handle)?;
+                        let mut buffer = Vec::new();
+                        let (smallest_key, _, _) = encoding::read_entry(
+                            &mut Cursor::new(block),
+                            &mut buffer,
+                            FormatVersion::Blocks,
+                        )?;
+                        Some((smallest_key, last_block.last_key.clone()))
+                    }
+                    _ => None,
+                };
+
+                Ok(SSTable {
+                    path,
+                    index: Some(Arc::new(index)),
+                    filter: filter.map(Arc::new),
+                    data_size: footer.index.offset,
+                    file_size,
+                    key_range,
+                    version: FormatVersion::Blocks,
+                    read_buffer_size,
+                    cache: cache.clone(),
+                    cache_id: cache.new_table_id(),
+                })
+            }
+        }
+    }
+
+    // Writes the entries, which must be in the order of MergingIterator, to a new sstable file.
+    // The file is synced to disk before returning.
+    pub fn create<'a, I>(
+        path: String,
+        entries: I,
+        options: &Options,
+        cache: &Arc<BlockCache>,
+    ) -> io::Result<SSTable>
+    where
+        I: IntoIterator<Item = (&'a Vec<u8>, u64, &'a Value)>,
+    {
+        let file = File::create(&path)?;
+        let mut writer = SSTableWriter::new(BufWriter::new(file), options);
+        for (key, sequence, value) in entries {
+            writer.add(key, sequence, value)?;
+        }
+        writer.finish_and_sync()?;
+
+        SSTable::open(path, options.read_buffer_size, cache)
+    }
+
+    fn get_reader(&self, buffer_size: usize) -> io::Result<BufReader<File>> {
+        let file = File::open(&self.path)?;
+        Ok(BufReader::with_capacity(buffer_size, file))
+    }
+
+    // Newest version of the key written at or before `sequence`.
+    pub fn get(&self, key: &[u8], sequence: u64) -> io::Result<Option<Value>> {
+        match &self.index {
+            None => {
+                let mut reader = self.get_reader(self.read_buffer_size)?.take(self.data_size);
+                encoding::find_value(&mut reader, key, sequence, self.version)
+                    .map_err(truncated_as_corruption)
+            }
+            Some(index) => {
+                // The first block with a last key bigger or equal than the key is the only one
+                // that can contain its versions.
+                let block_index = index.partition_point(|entry| &entry.last_key[..] < key);
+                let entry = match index.get(block_index) {
+                    Some(entry) => entry,
+                    None => return Ok(None),
+                };
+
+                let block = self.cached_block(&entry.handle, || {
+                    read_block(&mut File::open(&self.path)?, &entry.handle)
+                })?;
+                let block_size = block.len() as u64;
+                let mut reader = Cursor::new(block).take(block_size);
+                encoding::find_value(&mut reader, key, sequence, self.version)
+                    .map_err(truncated_as_corruption)
+            }
+        }
+    }
+
+    // Returns the data block from the cache, or reads it with `read` and adds it to the cache.
+    fn cached_block<F>(&self, handle: &BlockHandle, read: F) -> io::Result<Block>
+    where
+        F: FnOnce() -> io::Result<Vec<u8>>,
+    {
+        if let Some(block) = self.cache.get(self.cache_id, handle.offset) {
+            return Ok(block);
+        }
+        let block: Block = read()?.into();
+        self.cache
+            .insert(self.cache_id, handle.offset, block.clone());
+        Ok(block)
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    // Smallest and largest keys of the table, or None if it is empty.
+    pub fn key_range(&self) -> Option<(&[u8], &[u8])> {
+        self.key_range
+            .as_ref()
+            .map(|(smallest, largest)| (&smallest[..], &largest[..]))
+    }
+
+    // True if the table may have keys between `smallest` and `largest`, both included.
+    pub fn overlaps(&self, smallest: &[u8], largest: &[u8]) -> bool {
+        match self.key_range() {
+            Some((table_smallest, table_largest)) => {
+                table_smallest <= largest && table_largest >= smallest
+            }
+            None => false,
+        }
+    }
+
+    // False means that the key is definitely not in the table. Tables without filter may
+    // contain any key.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        match &self.filter {
+            Some(filter) => filter.may_contain(key),
+            None => true,
+        }
+    }
+
+    // Iterates all the entries of the table in key order.
+    pub fn iter(&self) -> io::Result<SSTableIterator> {
+        self.iter_with_buffer(&[], self.read_buffer_size)
+    }
+
+    // Iterates the entries in key order starting at the block that can contain `start`. Entries
+    // of that block before `start` are also returned, so they have to be skipped by the caller.
+    pub fn iter_from(&self, start: &[u8]) -> io::Result<SSTableIterator> {
+        self.iter_with_buffer(start, RANGE_READ_BUFFER_SIZE.min(self.read_buffer_size))
+    }
+
+    fn iter_with_buffer(&self, start: &[u8], buffer_size: usize) -> io::Result<SSTableIterator> {
+        let source = match &self.index {
+            None => Source::Stream(self.get_reader(buffer_size)?.take(self.data_size)),
+            Some(index) => Source::Blocks {
+                table: self.clone(),
+                reader: self.get_reader(buffer_size)?,
+                reader_offset: 0,
+                index: index.clone(),
+                next_block: index.partition_point(|entry| &entry.last_key[..] < start),
+                block: Cursor::new(Block::from(Vec::new())),
+            },
+        };
+
+        Ok(SSTableIterator {
+            source,
+            buffer: Vec::new(),
+            version: self.version,
+        })
+    }
+
+    pub fn delete_file(&self) -> io::Result<()> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+impl PartialEq for SSTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+pub struct SSTableIterator {
+    source: Source,
+    buffer: Vec<u8>,
+    version: FormatVersion,
+}
+
+enum Source {
+    // Legacy tables are read as a single list of entries.
+    Stream(io::Take<BufReader<File>>),
+    // Indexed tables are read one block at a time, so every block can be checked. Blocks are
+    // taken from the cache when possible, and the file is read sequentially while consecutive
+    // blocks miss. The file is opened when the iterator is created, so it can still be read if
+    // the table is removed by a compaction in the meantime.
+    Blocks {
+        table: SSTable,
+        reader: BufReader<File>,
+        reader_offset: u64,
+        index: Arc<Vec<IndexEntry>>,
+        next_block: usize,
+        block: Cursor<Block>,
+    },
+    Finished,
+}
+
+impl Iterator for SSTableIterator {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = match &mut self.source {
+            Source::Finished => return None,
+            Source::Stream(reader) => {
+                if reader.limit() == 0 {
+                    return None;
+                }
+                encoding::read_entry(reader, &mut self.buffer, self.version)
+            }
+            Source::Blocks {
+                table,
+                reader,
+                reader_offset,
+                index,
+                next_block,
+                block,
+            } => loop {
+                if (block.position() as usize) < block.get_ref().len() {
+                    break encoding::read_entry(block, &mut self.buffer, self.version);
+                }
+
+                let entry = index.get(*next_block)?;
+                *next_block += 1;
+                let result = table.cached_block(&entry.handle, || {
+                    // Keeps the buffered data if the block is already in the buffer.
+                    reader.seek_relative(entry.handle.offset as i64 - *reader_offset as i64)?;
+                    let contents = read_block_contents(reader, &entry.handle)?;
+                    *reader_offset = entry.handle.offset
+                        + entry.handle.size
+                        + encoding::BLOCK_TRAILER_SIZE as u64;
+                    Ok(contents)
+                });
+                match result {
+                    Ok(contents) => *block = Cursor::new(contents),
+                    Err(e) => break Err(e),
+                }
+            },
+        };
+
+        // Nothing after an error can be trusted, so the iterator ends.
+        if result.is_err() {
+            self.source = Source::Finished;
+        }
+        Some(result.map_err(truncated_as_corruption))
+    }
+}
+
+// Writes an sstable entry by entry, so tables bigger than memory can be written. Entries must be
+// added in the order of MergingIterator.
+pub struct SSTableWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    block: Vec<u8>,
+    // Key of the last entry added, which is the last key of the current block if it has entries.
+    last_key: Vec<u8>,
+    index: Vec<IndexEntry>,
+    bloom_bits_per_key: usize,
+    key_hashes: Vec<u64>,
+    compression: Compression,
+}
+
+impl<W: Write> SSTableWriter<W> {
+    // With 0 bits per key the table is written without bloom filter.
+    pub fn new(writer: W, options: &Options) -> Self {
+        SSTableWriter {
+            writer,
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            last_key: Vec::new(),
+            index: Vec::new(),
+            bloom_bits_per_key: options.bloom_bits_per_key,
+            key_hashes: Vec::new(),
+            compression: options.compression,
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8], sequence: u64, value: &Value) -> io::Result<()> {
+        let new_key = self.block.is_empty() && self.index.is_empty() || key != self.last_key;
+        // The block is closed when the next key starts, so versions of a key are never split.
+        if new_key && self.block.len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+
+        self.block
+            .append(&mut encoding::serialize_entry(key, sequence, value));
+        if new_key {
+            self.last_key.clear();
+            self.last_key.extend_from_slice(key);
+            if self.bloom_bits_per_key > 0 {
+                self.key_hashes.push(bloom::hash(key));
+            }
+        }
+        Ok(())
+    }
+
+    // Key of the last entry added. Empty if there are none.
+    pub fn last_key(&self) -> &[u8] {
+        &self.last_key
+    }
+
+    // Bytes written so far, including the current block. Used to split big outputs in several
+    // tables.
+    pub fn estimated_size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+
+        let mut block = std::mem::take(&mut self.block);
+        let handle = self.write_raw_block(&block)?;
+        self.index.push(IndexEntry {
+            last_key: self.last_key.clone(),
+            handle,
+        });
+        // Reuse the allocation for the next block.
+        block.clear();
+        self.block = block;
+        Ok(())
+    }
+
+    // The handle has the size of the block as written, which is smaller if it was compressed.
+    fn write_raw_block(&mut self, block: &[u8]) -> io::Result<BlockHandle> {
+        let (contents, trailer) = encoding::serialize_block(block, self.compression);
+        self.writer.write_all(&contents)?;
+        self.writer.write_all(&trailer)?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: contents.len() as u64,
+        };
+        self.offset += (contents.len() + trailer.len()) as u64;
+        Ok(handle)
+    }
+
+    // Writes the last data block, the index, the filter and the footer. Returns the inner
+    // writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+
+        let mut index_block = Vec::new();
+        for entry in &self.index {
+            let handle = encoding::serialize_block_handle(&entry.handle);
+            index_block.append(&mut encoding::serialize_datum(&entry.last_key));
+            index_block.append(&mut encoding::serialize_datum(&handle));
+        }
+        let index = self.write_raw_block(&index_block)?;
+
+        let filter_block = if self.bloom_bits_per_key > 0 {
+            BloomFilter::from_key_hashes(&self.key_hashes, self.bloom_bits_per_key).serialize()
+        } else {
+            Vec::new()
+        };
+        let filter = self.write_raw_block(&filter_block)?;
+
+        self.writer
+            .write_all(&encoding::serialize_footer(&Footer { index, filter }))?;
+        Ok(self.writer)
+    }
+}
+
+impl SSTableWriter<BufWriter<File>> {
+    pub fn finish_and_sync(self) -> io::Result<()> {
+        let file = self.finish()?.into_inner()?;
+        file.sync_all()
+    }
+}
+
+fn read_block(file: &mut File, handle: &BlockHandle) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(handle.offset))?;
+    read_block_contents(file, handle)
+}
+
+// Reads the block and its trailer from the current position of the reader, checks it and
+// decompresses it.
+fn read_block_contents<R: Read>(reader: &mut R, handle: &BlockHandle) -> io::Result<Vec<u8>> {
+    let size = handle.size as usize;
+    let mut block = vec![0u8; size + encoding::BLOCK_TRAILER_SIZE];
+    reader
+        .read_exact(&mut block)
+        .map_err(truncated_as_corruption)?;
+
+    let trailer = block.split_off(size);
+    encoding::deserialize_block(block, &trailer)
+}
+
+// The size of everything read from a table is known in advance, so reaching the end of the file
+// means that it is corrupted.
+fn truncated_as_corruption(error: io::Error) -> io::Error {
+    if error.kind() == io::ErrorKind::UnexpectedEof {
+        encoding::corruption_error("Unexpected end of sstable")
+    } else {
+        error
+    }
+}
+
+fn deserialize_index(index_block: &[u8]) -> io::Result<Vec<IndexEntry>> {
+    let mut reader = Cursor::new(index_block);
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut index = Vec::new();
+
+    while (reader.position() as usize) < index_block.len() {
+        let key_size = encoding::read_next_datum(&mut reader, &mut buffer, FormatVersion::Blocks)?;
+        let last_key = buffer[..key_size].to_vec();
+        let handle_size =
+            encoding::read_next_datum(&mut reader, &mut buffer, FormatVersion::Blocks)?;
+        let handle = encoding::deserialize_block_handle(&buffer[..handle_size])?;
+        index.push(IndexEntry { last_key, handle });
+    }
+
+    Ok(index)
+}
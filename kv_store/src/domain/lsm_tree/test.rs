@@ -2,9 +2,17 @@ extern crate rand;
 
 use super::*;
 
+// Sequence number of the entries written by the tests. It is shared by all of them, as it only
+// has to grow.
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+fn next_sequence() -> u64 {
+    NEXT_SEQUENCE.fetch_add(1, Ordering::SeqCst)
+}
+
 #[derive(Debug)]
 struct MockMemtable {
-    vec: Vec<(Vec<u8>, Vec<u8>)>,
+    vec: Vec<(Vec<u8>, u64, Value)>,
 }
 
 impl MemTable for MockMemtable {
@@ -12,30 +20,32 @@ impl MemTable for MockMemtable {
         MockMemtable { vec: vec![] }
     }
 
-    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        self.vec.retain(|p| p.0 != key);
-        self.vec.push((key, value));
+    fn set(&mut self, key: Vec<u8>, sequence: u64, value: Value) {
+        self.vec.retain(|p| p.0 != key || p.1 != sequence);
+        self.vec.push((key, sequence, value));
     }
 
-    fn get(&self, key: &Vec<u8>) -> Option<&Vec<u8>> {
-        let pair = self.vec.iter().find(|&x| x.0 == *key);
-        match pair {
-            Some(p) => Some(&p.1),
-            None => None,
-        }
+    fn get(&self, key: &[u8], sequence: u64) -> Option<&Value> {
+        self.sorted_entries()
+            .into_iter()
+            .find(|p| p.0 == key && p.1 <= sequence)
+            .map(|p| p.2)
     }
 
-    fn len(&self) -> usize {
-        self.vec.len()
+    fn memory_usage(&self) -> usize {
+        self.vec
+            .iter()
+            .map(|(key, _, value)| crate::domain::entry_memory_usage(key.len(), value))
+            .sum()
     }
 
-    fn sorted_entries(&self) -> Vec<(&Vec<u8>, &Vec<u8>)> {
+    fn sorted_entries(&self) -> Vec<(&Vec<u8>, u64, &Value)> {
         let mut ret = vec![];
         for i in 0..self.vec.len() {
             let p = &self.vec[i];
-            ret.push((&p.0, &p.1));
+            ret.push((&p.0, p.1, &p.2));
         }
-        ret.sort_by(|p1, p2| p1.0.cmp(&p2.0));
+        ret.sort_by(|p1, p2| p1.0.cmp(p2.0).then(p2.1.cmp(&p1.1)));
         ret
     }
 }
@@ -46,24 +56,39 @@ macro_rules! byte_vec {
     };
 }
 
+macro_rules! data {
+    ($a: expr) => {
+        Value::Data(String::from($a).into_bytes())
+    };
+}
+
+fn into_memtable(values: Vec<(Vec<u8>, Vec<u8>)>) -> MockMemtable {
+    MockMemtable {
+        vec: values
+            .into_iter()
+            .map(|(key, value)| (key, next_sequence(), Value::Data(value)))
+            .collect(),
+    }
+}
+
 fn create_lsm_tree_in_tmp_folder() -> (LSMTree<MockMemtable>, String) {
     let test_dir = format!("./tmp-{}/", rand::random::<u64>());
 
-    let lsm_tree = LSMTree::new(&test_dir);
+    let lsm_tree = LSMTree::open(&test_dir, &Options::default()).unwrap();
 
     (lsm_tree, test_dir)
 }
 
 fn add_sstable_to_tree(lsm_tree: &mut LSMTree<MockMemtable>, values: Vec<(Vec<u8>, Vec<u8>)>) {
-    let memtable = MockMemtable { vec: values };
+    let memtable = into_memtable(values);
 
-    lsm_tree.save_memtable(memtable);
+    lsm_tree.save_memtable(memtable, vec![]);
 }
 
 fn add_sstable_to_tree_and_merge(lsm_tree: &mut LSMTree<MockMemtable>, values: Vec<(Vec<u8>, Vec<u8>)>) {
-    let memtable = MockMemtable { vec: values };
+    let memtable = into_memtable(values);
 
-    lsm_tree._save_memtable(memtable, true);
+    lsm_tree._save_memtable(memtable, vec![], true);
 }
 
 #[test]
@@ -84,19 +109,19 @@ fn test_save_and_get() {
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("fruita"))
+            .get(&byte_vec!("fruita"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("poma")
+        data!("poma")
     );
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("ciutat"))
+            .get(&byte_vec!("ciutat"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Barcelona city")
+        data!("Barcelona city")
     );
 
-    assert_eq!(lsm_tree.get(&byte_vec!("mandarina")), None);
+    assert_eq!(lsm_tree.get(&byte_vec!("mandarina"), u64::MAX).unwrap(), None);
 
     std::mem::drop(lsm_tree);
 
@@ -105,7 +130,7 @@ fn test_save_and_get() {
 
 
 #[test]
-fn test_save_waits_for_previous_save() {
+fn test_save_before_previous_save_finishes() {
     let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
 
     add_sstable_to_tree(
@@ -115,7 +140,7 @@ fn test_save_waits_for_previous_save() {
             (byte_vec!("ciutat"), byte_vec!("Barcelona city")),
         ],
     );
-    // This should wait for the previous save to finish.
+    // Both memtables can be read while they wait to be saved.
     add_sstable_to_tree(
         &mut lsm_tree,
         vec![
@@ -126,26 +151,26 @@ fn test_save_waits_for_previous_save() {
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("fruita"))
+            .get(&byte_vec!("fruita"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("poma")
+        data!("poma")
     );
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("ciutat"))
+            .get(&byte_vec!("ciutat"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Mataró city")
+        data!("Mataró city")
     );
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("cotxe"))
+            .get(&byte_vec!("cotxe"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Honda")
+        data!("Honda")
     );
 
-    assert_eq!(lsm_tree.get(&byte_vec!("mandarina")), None);
+    assert_eq!(lsm_tree.get(&byte_vec!("mandarina"), u64::MAX).unwrap(), None);
 
     std::mem::drop(lsm_tree);
 
@@ -217,69 +242,922 @@ fn test_merge_tables() {
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("fruita"))
+            .get(&byte_vec!("fruita"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("mandarina")
+        data!("mandarina")
     );
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("ciutat"))
+            .get(&byte_vec!("ciutat"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Sabadell")
+        data!("Sabadell")
     );
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("cotxe"))
+            .get(&byte_vec!("cotxe"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Honda")
+        data!("Honda")
     );
 
     assert_eq!(
         lsm_tree
-            .get(&byte_vec!("nom"))
+            .get(&byte_vec!("nom"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Gerard")
+        data!("Gerard")
     );
 
-    assert_eq!(lsm_tree.get(&byte_vec!("coffee")), None);
+    assert_eq!(lsm_tree.get(&byte_vec!("coffee"), u64::MAX).unwrap(), None);
 
     std::mem::drop(lsm_tree);
 
-    let new_lsm_tree = LSMTree::<MockMemtable>::new(&tmp_dir);
+    let new_lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
 
     assert_eq!(
         new_lsm_tree
-            .get(&byte_vec!("fruita"))
+            .get(&byte_vec!("fruita"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("mandarina")
+        data!("mandarina")
     );
 
     assert_eq!(
         new_lsm_tree
-            .get(&byte_vec!("ciutat"))
+            .get(&byte_vec!("ciutat"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Sabadell")
+        data!("Sabadell")
     );
 
     assert_eq!(
         new_lsm_tree
-            .get(&byte_vec!("cotxe"))
+            .get(&byte_vec!("cotxe"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Honda")
+        data!("Honda")
     );
 
     assert_eq!(
         new_lsm_tree
-            .get(&byte_vec!("nom"))
+            .get(&byte_vec!("nom"), u64::MAX).unwrap()
             .expect("Value should be found"),
-        byte_vec!("Gerard")
+        data!("Gerard")
     );
 
-    assert_eq!(new_lsm_tree.get(&byte_vec!("coffee")), None);
+    assert_eq!(new_lsm_tree.get(&byte_vec!("coffee"), u64::MAX).unwrap(), None);
 
     std::mem::drop(new_lsm_tree);
 
     fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
 }
+
+#[test]
+fn test_get_in_table_with_many_blocks() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    let values: Vec<(Vec<u8>, Vec<u8>)> = (0..2_000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), format!("value {}", i).into_bytes()))
+        .collect();
+    add_sstable_to_tree(&mut lsm_tree, values);
+    lsm_tree.wait_for_threads();
+
+    for i in (0..2_000u32).step_by(7) {
+        assert_eq!(
+            lsm_tree
+                .get(&i.to_be_bytes(), u64::MAX).unwrap()
+                .expect("Value should be found"),
+            Value::Data(format!("value {}", i).into_bytes())
+        );
+    }
+    assert_eq!(lsm_tree.get(&2_000u32.to_be_bytes(), u64::MAX).unwrap(), None);
+    assert_eq!(lsm_tree.get(&[0, 0, 0], u64::MAX).unwrap(), None);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_read_and_merge_legacy_tables() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    fs::create_dir(&tmp_dir).expect("Create tmp folder");
+
+    // Tables written before the block format are just a list of entries, with deletions stored as
+    // a magic value.
+    let mut legacy_table = Vec::new();
+    for datum in [
+        &b"ciutat"[..],
+        b"Barcelona city",
+        b"cotxe",
+        &encoding::LEGACY_TOMBSTONE,
+        b"fruita",
+        b"poma",
+    ] {
+        legacy_table.extend_from_slice(&(datum.len() as u16).to_be_bytes());
+        legacy_table.extend_from_slice(datum);
+    }
+    fs::write(format!("{}/00000000.sstable", tmp_dir), legacy_table).expect("Write legacy table");
+
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+
+    assert_eq!(
+        lsm_tree
+            .get(&byte_vec!("fruita"), u64::MAX).unwrap()
+            .expect("Value should be found"),
+        data!("poma")
+    );
+    assert_eq!(lsm_tree.get(&byte_vec!("cotxe"), u64::MAX).unwrap(), Some(Value::Tombstone));
+    assert_eq!(lsm_tree.get(&byte_vec!("moto"), u64::MAX).unwrap(), None);
+
+    add_sstable_to_tree_and_merge(
+        &mut lsm_tree,
+        vec![(byte_vec!("ciutat"), byte_vec!("Mataró city"))],
+    );
+    lsm_tree.wait_for_threads();
+    assert_eq!(lsm_tree.len(), 1);
+
+    assert_eq!(
+        lsm_tree
+            .get(&byte_vec!("fruita"), u64::MAX).unwrap()
+            .expect("Value should be found"),
+        data!("poma")
+    );
+    assert_eq!(
+        lsm_tree
+            .get(&byte_vec!("ciutat"), u64::MAX).unwrap()
+            .expect("Value should be found"),
+        data!("Mataró city")
+    );
+    // The legacy tombstone is read as a deletion, and the merge drops it, as it has every table.
+    assert_eq!(lsm_tree.get(&byte_vec!("cotxe"), u64::MAX).unwrap(), None);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_bloom_filter_skips_tables() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    std::mem::drop(lsm_tree);
+
+    let options = Options::builder().bloom_bits_per_key(0).build().unwrap();
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &options).unwrap();
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("cotxe"), byte_vec!("Honda"))]);
+    lsm_tree.wait_for_threads();
+
+    // The newest table has no filter, so only the oldest one can be skipped.
+    assert_eq!(lsm_tree.get(&byte_vec!("cotxe"), u64::MAX).unwrap(), Some(data!("Honda")));
+    assert_eq!(lsm_tree.stats().bloom_filter_checks, 1);
+    assert_eq!(lsm_tree.stats().bloom_filter_useful, 0);
+
+    assert_eq!(lsm_tree.get(&byte_vec!("mandarina"), u64::MAX).unwrap(), None);
+    assert_eq!(lsm_tree.stats().bloom_filter_checks, 3);
+    assert_eq!(lsm_tree.stats().bloom_filter_useful, 1);
+
+    // Filters are loaded from disk when the tree is opened again.
+    std::mem::drop(lsm_tree);
+    let lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("poma")));
+    assert_eq!(lsm_tree.get(&byte_vec!("mandarina"), u64::MAX).unwrap(), None);
+    assert_eq!(lsm_tree.stats().bloom_filter_useful, 1);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+fn level_paths(lsm_tree: &LSMTree<MockMemtable>, level: usize) -> Vec<String> {
+    let levels = lsm_tree.storage.levels.read().unwrap();
+    levels
+        .level(level)
+        .iter()
+        .map(|sstable| sstable.path.clone())
+        .collect()
+}
+
+#[test]
+fn test_level_0_is_compacted_into_level_1() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    for i in 0..Options::default().l0_compaction_trigger {
+        add_sstable_to_tree(
+            &mut lsm_tree,
+            vec![
+                (byte_vec!("ciutat"), format!("city {}", i).into_bytes()),
+                (format!("key {}", i).into_bytes(), byte_vec!("value")),
+            ],
+        );
+    }
+    lsm_tree.wait_for_threads();
+
+    assert_eq!(level_paths(&lsm_tree, 0).len(), 0);
+    assert_eq!(level_paths(&lsm_tree, 1).len(), 1);
+    assert_eq!(
+        lsm_tree.get(&byte_vec!("ciutat"), u64::MAX).unwrap(),
+        Some(Value::Data(
+            format!("city {}", Options::default().l0_compaction_trigger - 1).into_bytes()
+        ))
+    );
+
+    // Levels are loaded from disk when the tree is opened again.
+    let level_1 = level_paths(&lsm_tree, 1);
+    std::mem::drop(lsm_tree);
+    let lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    assert_eq!(level_paths(&lsm_tree, 0).len(), 0);
+    assert_eq!(level_paths(&lsm_tree, 1), level_1);
+    assert_eq!(lsm_tree.get(&byte_vec!("key 0"), u64::MAX).unwrap(), Some(data!("value")));
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_compaction_only_rewrites_overlapping_tables() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    // Without compression, so the values below take enough space.
+    let options = Options::builder()
+        .compression(crate::domain::options::Compression::None)
+        .build()
+        .unwrap();
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &options).unwrap();
+
+    // Enough data to be split in several level 1 tables.
+    let value = vec![7u8; 200];
+    for batch in 0..Options::default().l0_compaction_trigger as u32 {
+        let values: Vec<(Vec<u8>, Vec<u8>)> = (batch * 5_000..(batch + 1) * 5_000)
+            .map(|i| (i.to_be_bytes().to_vec(), value.clone()))
+            .collect();
+        add_sstable_to_tree(&mut lsm_tree, values);
+    }
+    lsm_tree.wait_for_threads();
+
+    let level_1 = level_paths(&lsm_tree, 1);
+    assert!(level_1.len() > 1, "{} tables in level 1", level_1.len());
+
+    // New values for the first keys only overlap the first table of level 1.
+    for i in 0..Options::default().l0_compaction_trigger as u32 {
+        add_sstable_to_tree(
+            &mut lsm_tree,
+            vec![(i.to_be_bytes().to_vec(), byte_vec!("new"))],
+        );
+    }
+    lsm_tree.wait_for_threads();
+
+    let new_level_1 = level_paths(&lsm_tree, 1);
+    assert_eq!(level_paths(&lsm_tree, 0).len(), 0);
+    assert_ne!(new_level_1[0], level_1[0]);
+    assert_eq!(new_level_1[1..], level_1[1..]);
+
+    assert_eq!(lsm_tree.get(&0u32.to_be_bytes(), u64::MAX).unwrap(), Some(data!("new")));
+    assert_eq!(
+        lsm_tree.get(&5u32.to_be_bytes(), u64::MAX).unwrap(),
+        Some(Value::Data(value.clone()))
+    );
+    assert_eq!(
+        lsm_tree.get(&19_999u32.to_be_bytes(), u64::MAX).unwrap(),
+        Some(Value::Data(value))
+    );
+    assert_eq!(lsm_tree.get(&20_000u32.to_be_bytes(), u64::MAX).unwrap(), None);
+
+    let keys: Vec<Vec<u8>> = lsm_tree
+        .range(&(Bound::Unbounded, Bound::Unbounded), u64::MAX)
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let expected: Vec<Vec<u8>> = (0..20_000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+    assert_eq!(keys, expected);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_open_only_loads_tables_in_manifest() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![(byte_vec!("cotxe"), byte_vec!("Honda"))]);
+    lsm_tree.wait_for_threads();
+    let merged_paths = level_paths(&lsm_tree, 1);
+    std::mem::drop(lsm_tree);
+
+    // Leftovers of a flush and a merge that didn't finish.
+    let stray_table = format!("{}/00000042.sstable", tmp_dir);
+    let stray_tmp = format!("{}/00000007.sstable.tmp", tmp_dir);
+    fs::write(&stray_table, b"not a table").expect("Write stray table");
+    fs::write(&stray_tmp, b"not a table").expect("Write stray tmp file");
+
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    assert!(!Path::new(&stray_table).exists());
+    assert!(!Path::new(&stray_tmp).exists());
+    assert_eq!(level_paths(&lsm_tree, 1), merged_paths);
+
+    // New tables never reuse the name of an existing one.
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("ciutat"), byte_vec!("Mataró city"))]);
+    lsm_tree.wait_for_threads();
+    let new_paths = level_paths(&lsm_tree, 0);
+    assert_eq!(new_paths.len(), 1);
+    assert!(!merged_paths.contains(&new_paths[0]));
+
+    std::mem::drop(lsm_tree);
+    let lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("poma")));
+    assert_eq!(lsm_tree.get(&byte_vec!("cotxe"), u64::MAX).unwrap(), Some(data!("Honda")));
+    assert_eq!(lsm_tree.get(&byte_vec!("ciutat"), u64::MAX).unwrap(), Some(data!("Mataró city")));
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_save_errors_are_kept_for_the_caller() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    // Without the directory the sstable can't be created.
+    fs::remove_dir_all(&tmp_dir).expect("Remove tmp folder");
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    lsm_tree.wait_for_threads();
+
+    assert!(lsm_tree.background_error().is_some());
+    // The memtable that couldn't be saved can still be read.
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("poma")));
+}
+
+#[test]
+fn test_blocks_are_cached() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    let values: Vec<(Vec<u8>, Vec<u8>)> = (0..2_000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), format!("value {}", i).into_bytes()))
+        .collect();
+    add_sstable_to_tree(&mut lsm_tree, values);
+    lsm_tree.wait_for_threads();
+
+    let key = 1_000u32.to_be_bytes().to_vec();
+    assert_eq!(lsm_tree.get(&key, u64::MAX).unwrap(), Some(data!("value 1000")));
+    assert_eq!(lsm_tree.get(&key, u64::MAX).unwrap(), Some(data!("value 1000")));
+    assert_eq!(lsm_tree.stats().block_cache_misses, 1);
+    assert_eq!(lsm_tree.stats().block_cache_hits, 1);
+
+    // The iterator reads the blocks around the cached one from the file.
+    let entries: Vec<_> = lsm_tree
+        .range(&(Bound::Unbounded, Bound::Unbounded), u64::MAX)
+        .map(|entry| entry.unwrap())
+        .collect();
+    assert_eq!(entries.len(), 2_000);
+    for (i, (key, _, value)) in entries.into_iter().enumerate() {
+        assert_eq!(key, (i as u32).to_be_bytes().to_vec());
+        assert_eq!(value, Value::Data(format!("value {}", i).into_bytes()));
+    }
+    assert_eq!(lsm_tree.stats().block_cache_hits, 2);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_tables_with_different_compression() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    let json = |i: u32| format!("{{\"id\": {}, \"name\": \"user {}\"}}", i, i).into_bytes();
+
+    let options = Options::builder()
+        .compression(crate::domain::options::Compression::None)
+        .build()
+        .unwrap();
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &options).unwrap();
+    add_sstable_to_tree(
+        &mut lsm_tree,
+        (0..1_000u32).map(|i| (i.to_be_bytes().to_vec(), json(i))).collect(),
+    );
+    std::mem::drop(lsm_tree);
+
+    // Each block records its compression, so both tables can be read and merged.
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    add_sstable_to_tree(
+        &mut lsm_tree,
+        (1_000..2_000u32).map(|i| (i.to_be_bytes().to_vec(), json(i))).collect(),
+    );
+    lsm_tree.wait_for_threads();
+    let sizes: Vec<u64> = lsm_tree
+        .storage
+        .levels
+        .read()
+        .unwrap()
+        .level(0)
+        .iter()
+        .map(SSTable::file_size)
+        .collect();
+    assert!(sizes[1] * 2 < sizes[0], "{:?}", sizes);
+    assert_eq!(lsm_tree.get(&5u32.to_be_bytes(), u64::MAX).unwrap(), Some(Value::Data(json(5))));
+    assert_eq!(
+        lsm_tree.get(&1_500u32.to_be_bytes(), u64::MAX).unwrap(),
+        Some(Value::Data(json(1_500)))
+    );
+
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![]);
+    lsm_tree.wait_for_threads();
+    let entries = lsm_tree.range(&(Bound::Unbounded, Bound::Unbounded), u64::MAX).count();
+    assert_eq!(entries, 2_000);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_compaction_keeps_versions_of_snapshots() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    lsm_tree.wait_for_threads();
+    let snapshot = lsm_tree.snapshots().acquire(&AtomicU64::new(lsm_tree.last_sequence()));
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("pera"))]);
+    lsm_tree.wait_for_threads();
+
+    assert_eq!(lsm_tree.len(), 1);
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("pera")));
+    assert_eq!(
+        lsm_tree.get(&byte_vec!("fruita"), snapshot.sequence()).unwrap(),
+        Some(data!("poma"))
+    );
+    let entries: Vec<merge_iterator::Entry> = lsm_tree
+        .range(&(Bound::Unbounded, Bound::Unbounded), snapshot.sequence())
+        .map(|entry| entry.unwrap())
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].2, data!("poma"));
+
+    // Once the snapshot is dropped, the next compaction removes the old version.
+    let sequence = snapshot.sequence();
+    std::mem::drop(snapshot);
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![]);
+    lsm_tree.wait_for_threads();
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), sequence).unwrap(), None);
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("pera")));
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_versions_of_a_key_are_not_split() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+    let value = |sequence: u64| Value::Data(format!("value {:0100}", sequence).into_bytes());
+
+    // Many more versions than fit in a block. The snapshot keeps all of them.
+    let snapshot = lsm_tree.snapshots().acquire(&AtomicU64::new(1));
+    let mut memtable = MockMemtable::new();
+    for sequence in 1..=1_000 {
+        memtable.set(byte_vec!("a"), sequence, value(sequence));
+    }
+    memtable.set(byte_vec!("b"), 1_001, data!("b"));
+    lsm_tree.save_memtable(memtable, vec![]);
+    lsm_tree.wait_for_threads();
+
+    let check_versions = |lsm_tree: &LSMTree<MockMemtable>| {
+        assert_eq!(lsm_tree.get(&byte_vec!("a"), 0).unwrap(), None);
+        for sequence in (1..=1_000).step_by(99) {
+            assert_eq!(lsm_tree.get(&byte_vec!("a"), sequence).unwrap(), Some(value(sequence)));
+        }
+        assert_eq!(lsm_tree.get(&byte_vec!("b"), u64::MAX).unwrap(), Some(data!("b")));
+    };
+    check_versions(&lsm_tree);
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![]);
+    lsm_tree.wait_for_threads();
+    check_versions(&lsm_tree);
+
+    std::mem::drop(snapshot);
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_compaction_drops_expired_values() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+    let expiring = |data: &str, expires_at: u64| Value::Expiring {
+        data: byte_vec!(data),
+        expires_at,
+    };
+
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    lsm_tree.wait_for_threads();
+    let snapshot = lsm_tree.snapshots().acquire(&AtomicU64::new(lsm_tree.last_sequence()));
+
+    let mut memtable = MockMemtable::new();
+    memtable.set(byte_vec!("ciutat"), next_sequence(), expiring("Barcelona", 1));
+    memtable.set(byte_vec!("fruita"), next_sequence(), expiring("pera", 1));
+    memtable.set(byte_vec!("verdura"), next_sequence(), expiring("col", u64::MAX));
+    lsm_tree._save_memtable(memtable, vec![], true);
+    lsm_tree.wait_for_threads();
+
+    // Nothing is older than the expired city, so it is gone. The expired fruit hides the version
+    // that the snapshot reads, so it is kept as a tombstone.
+    let entries: Vec<merge_iterator::Entry> = lsm_tree
+        .range(&(Bound::Unbounded, Bound::Unbounded), u64::MAX)
+        .map(|entry| entry.unwrap())
+        .collect();
+    let values: Vec<(Vec<u8>, Value)> =
+        entries.into_iter().map(|(key, _, value)| (key, value)).collect();
+    assert_eq!(
+        values,
+        vec![
+            (byte_vec!("fruita"), Value::Tombstone),
+            (byte_vec!("verdura"), expiring("col", u64::MAX)),
+        ]
+    );
+    assert_eq!(
+        lsm_tree.get(&byte_vec!("fruita"), snapshot.sequence()).unwrap(),
+        Some(data!("poma"))
+    );
+
+    // Without the snapshot, the old version is dropped, and then the tombstone too.
+    let sequence = snapshot.sequence();
+    std::mem::drop(snapshot);
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![]);
+    lsm_tree.wait_for_threads();
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), sequence).unwrap(), None);
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), None);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_saves_stall_when_the_queue_is_full() {
+    let (lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+    let max_memtables = Options::default().max_immutable_memtables;
+
+    // The worker can't add the tables to the levels until the lock is released.
+    let levels = lsm_tree.storage.levels.write().unwrap();
+    for i in 0..max_memtables {
+        let memtable = into_memtable(vec![(byte_vec!("fruita"), format!("{}", i).into_bytes())]);
+        lsm_tree.save_memtable(memtable, vec![]);
+    }
+    assert_eq!(lsm_tree.stats().write_stalls, 0);
+    // The newest memtable has the newest version.
+    assert_eq!(
+        lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(),
+        Some(Value::Data(format!("{}", max_memtables - 1).into_bytes()))
+    );
+
+    thread::scope(|scope| {
+        let stalled_save = scope.spawn(|| {
+            let memtable = into_memtable(vec![(byte_vec!("fruita"), byte_vec!("last"))]);
+            lsm_tree.save_memtable(memtable, vec![]);
+        });
+        thread::sleep(std::time::Duration::from_millis(100));
+        assert!(!stalled_save.is_finished());
+        assert_eq!(lsm_tree.stats().write_stalls, 1);
+
+        std::mem::drop(levels);
+        stalled_save.join().unwrap();
+    });
+    lsm_tree.wait_for_threads();
+
+    assert!(lsm_tree.flush_queue.memtables().is_empty());
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("last")));
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_paused_compactions_wait_until_resumed() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+    let trigger = Options::default().l0_compaction_trigger;
+
+    lsm_tree.pause_compactions();
+    for i in 0..trigger {
+        let city = format!("city {}", i).into_bytes();
+        add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("ciutat"), city)]);
+    }
+    // Waiting for the threads doesn't wait for paused compactions.
+    lsm_tree.wait_for_threads();
+    assert_eq!(level_paths(&lsm_tree, 0).len(), trigger);
+    assert_eq!(lsm_tree.stats().compactions, 0);
+
+    lsm_tree.resume_compactions();
+    lsm_tree.wait_for_threads();
+    assert_eq!(level_paths(&lsm_tree, 0).len(), 0);
+    assert_eq!(level_paths(&lsm_tree, 1).len(), 1);
+    assert_eq!(lsm_tree.stats().compactions, 1);
+    assert_eq!(
+        lsm_tree.get(&byte_vec!("ciutat"), u64::MAX).unwrap(),
+        Some(Value::Data(format!("city {}", trigger - 1).into_bytes()))
+    );
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_level_0_is_compacted_when_idle() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    let options = Options::builder()
+        .idle_compaction_delay(std::time::Duration::from_millis(200))
+        .build()
+        .unwrap();
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &options).unwrap();
+
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    lsm_tree.wait_for_threads();
+    // Less tables than the trigger, so only the idle trigger compacts them.
+    assert_eq!(level_paths(&lsm_tree, 0).len(), 1);
+
+    for _ in 0..200 {
+        if level_paths(&lsm_tree, 0).is_empty() {
+            break;
+        }
+        thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert_eq!(level_paths(&lsm_tree, 0).len(), 0);
+    assert_eq!(level_paths(&lsm_tree, 1).len(), 1);
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("poma")));
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_compaction_of_level_0_range() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    lsm_tree.pause_compactions();
+    let tables: Vec<Vec<(&str, &str)>> = vec![
+        vec![("a", "1"), ("b", "1")],
+        vec![("a", "2")],
+        vec![("b", "2"), ("c", "2")],
+        vec![("a", "3")],
+    ];
+    for table in tables {
+        let values = table
+            .into_iter()
+            .map(|(key, value)| (byte_vec!(key), byte_vec!(value)))
+            .collect();
+        add_sstable_to_tree(&mut lsm_tree, values);
+    }
+    lsm_tree.wait_for_threads();
+    let tables = level_paths(&lsm_tree, 0);
+    assert_eq!(tables.len(), 4);
+
+    // While level 1 is busy, two adjacent tables are merged inside level 0.
+    let mut busy_levels = [false; NUM_LEVELS];
+    busy_levels[1] = true;
+    let compaction = lsm_tree.storage.levels.read().unwrap().pick_compaction(&busy_levels);
+    let compaction = compaction.unwrap();
+    assert_eq!(compaction.output_level, 0);
+    assert_eq!(compaction.inputs.len(), 2);
+
+    // The output is newer than the first table and older than the last one, although its file
+    // number is the biggest.
+    let compaction = lsm_tree.storage.levels.read().unwrap().compaction_of_level_0_range(1..3);
+    run_compaction(&lsm_tree.storage, compaction.unwrap()).unwrap();
+    let compacted_tables = level_paths(&lsm_tree, 0);
+    assert_eq!(compacted_tables.len(), 3);
+    assert_eq!(compacted_tables[0], tables[0]);
+    assert!(!tables.contains(&compacted_tables[1]));
+    assert_eq!(compacted_tables[2], tables[3]);
+
+    let check_values = |lsm_tree: &LSMTree<MockMemtable>| {
+        assert_eq!(lsm_tree.get(&byte_vec!("a"), u64::MAX).unwrap(), Some(data!("3")));
+        assert_eq!(lsm_tree.get(&byte_vec!("b"), u64::MAX).unwrap(), Some(data!("2")));
+        assert_eq!(lsm_tree.get(&byte_vec!("c"), u64::MAX).unwrap(), Some(data!("2")));
+        let keys: Vec<_> = lsm_tree
+            .range(&(Bound::Unbounded, Bound::Unbounded), u64::MAX)
+            .map(|entry| entry.unwrap())
+            .map(|(key, _, value)| (key, value))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (byte_vec!("a"), data!("3")),
+                (byte_vec!("b"), data!("2")),
+                (byte_vec!("c"), data!("2")),
+            ]
+        );
+    };
+    check_values(&lsm_tree);
+
+    // The order of level 0 is kept when the tree is opened again.
+    std::mem::drop(lsm_tree);
+    let lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    lsm_tree.pause_compactions();
+    assert_eq!(level_paths(&lsm_tree, 0), compacted_tables);
+    check_values(&lsm_tree);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_open_after_compaction_crashes() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    lsm_tree.pause_compactions();
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("ciutat"), byte_vec!("girona"))]);
+    lsm_tree.wait_for_threads();
+    let tables = level_paths(&lsm_tree, 0);
+    std::mem::drop(lsm_tree);
+
+    // A compaction that moves the first table to level 1 wrote its output, and was writing the
+    // line of the manifest when it crashed.
+    let output = sstable_path(&tmp_dir, 100);
+    let input_number = file_number(&tables[0]).unwrap();
+    let edit = format!("next_file 101 last_sequence 0 remove {} add 1 100", input_number);
+    let manifest_path = format!("{}/MANIFEST", tmp_dir);
+    let append_to_manifest = |line: &str| {
+        use std::io::Write;
+        let mut manifest = fs::OpenOptions::new().append(true).open(&manifest_path).unwrap();
+        manifest.write_all(line.as_bytes()).unwrap();
+    };
+    fs::copy(&tables[0], &output).expect("Copy table");
+    append_to_manifest(&edit);
+
+    let lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    lsm_tree.pause_compactions();
+    assert!(!Path::new(&output).exists());
+    assert_eq!(level_paths(&lsm_tree, 0), tables);
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("poma")));
+    std::mem::drop(lsm_tree);
+
+    // This time it crashed after writing the line, before removing the input.
+    fs::copy(&tables[0], &output).expect("Copy table");
+    append_to_manifest(&format!("{}\n", edit));
+
+    let lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &Options::default()).unwrap();
+    assert!(!Path::new(&tables[0]).exists());
+    assert_eq!(level_paths(&lsm_tree, 0), vec![tables[1].clone()]);
+    assert_eq!(level_paths(&lsm_tree, 1), vec![output]);
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), Some(data!("poma")));
+    assert_eq!(lsm_tree.get(&byte_vec!("ciutat"), u64::MAX).unwrap(), Some(data!("girona")));
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_compaction_drops_tombstones() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    lsm_tree.pause_compactions();
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("cotxe"), byte_vec!("Honda"))]);
+    lsm_tree.wait_for_threads();
+    let car_sequence = lsm_tree.last_sequence();
+    let mut memtable = MockMemtable::new();
+    memtable.set(byte_vec!("cotxe"), next_sequence(), Value::Tombstone);
+    memtable.set(byte_vec!("fruita"), next_sequence(), Value::Tombstone);
+    lsm_tree.save_memtable(memtable, vec![]);
+    lsm_tree.wait_for_threads();
+
+    // The older table has a version of the car, so its tombstone is kept. Nothing older has the
+    // fruit.
+    let compaction = lsm_tree.storage.levels.read().unwrap().compaction_of_level_0_range(1..2);
+    let stats = run_compaction(&lsm_tree.storage, compaction.unwrap()).unwrap();
+    assert_eq!(stats, CompactionStats { dropped_tombstones: 1, dropped_versions: 0 });
+    assert_eq!(lsm_tree.get(&byte_vec!("cotxe"), u64::MAX).unwrap(), Some(Value::Tombstone));
+    assert_eq!(lsm_tree.get(&byte_vec!("fruita"), u64::MAX).unwrap(), None);
+
+    // A snapshot that reads the car keeps both versions.
+    let snapshot = lsm_tree.snapshots().acquire(&AtomicU64::new(car_sequence));
+    lsm_tree.resume_compactions();
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![]);
+    lsm_tree.wait_for_threads();
+    assert_eq!(lsm_tree.stats().dropped_tombstones, 0);
+    assert_eq!(lsm_tree.stats().dropped_versions, 0);
+    assert_eq!(
+        lsm_tree.get(&byte_vec!("cotxe"), snapshot.sequence()).unwrap(),
+        Some(data!("Honda"))
+    );
+
+    // Without it, a full compaction has every version, so nothing is left.
+    std::mem::drop(snapshot);
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![]);
+    lsm_tree.wait_for_threads();
+    assert_eq!(lsm_tree.stats().dropped_tombstones, 1);
+    assert_eq!(lsm_tree.stats().dropped_versions, 1);
+    assert_eq!(lsm_tree.get(&byte_vec!("cotxe"), u64::MAX).unwrap(), None);
+    assert_eq!(lsm_tree.len(), 0);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_compaction_of_big_level_0_tables_writes_one_table() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    // Without compression, so the tables are bigger than the size at which outputs are split.
+    let options = Options::builder()
+        .compression(crate::domain::options::Compression::None)
+        .l0_compaction_trigger(2)
+        .build()
+        .unwrap();
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &options).unwrap();
+
+    lsm_tree.pause_compactions();
+    let value = vec![7u8; 1000];
+    for batch in 0..2u32 {
+        let values: Vec<(Vec<u8>, Vec<u8>)> = (batch * 3_000..(batch + 1) * 3_000)
+            .map(|i| (i.to_be_bytes().to_vec(), value.clone()))
+            .collect();
+        add_sstable_to_tree(&mut lsm_tree, values);
+    }
+    lsm_tree.wait_for_threads();
+    let tables = level_paths(&lsm_tree, 0);
+    assert_eq!(tables.len(), 2);
+    let table_size = |path: &String| fs::metadata(path).unwrap().len();
+    assert!(tables.iter().all(|path| table_size(path) > TARGET_SSTABLE_SIZE));
+
+    let mut busy_levels = [false; NUM_LEVELS];
+    busy_levels[1] = true;
+    let compaction = lsm_tree.storage.levels.read().unwrap().pick_compaction(&busy_levels);
+    run_compaction(&lsm_tree.storage, compaction.unwrap()).unwrap();
+
+    assert_eq!(level_paths(&lsm_tree, 0).len(), 1);
+    assert_eq!(
+        lsm_tree.get(&5_999u32.to_be_bytes(), u64::MAX).unwrap(),
+        Some(Value::Data(value))
+    );
+    // With a single table there is nothing to merge inside level 0.
+    let compaction = lsm_tree.storage.levels.read().unwrap().pick_compaction(&busy_levels);
+    assert!(compaction.is_none());
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_range_keeps_working_after_compaction() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    // Without cache, so every block is read from the files.
+    let options = Options::builder()
+        .compression(crate::domain::options::Compression::None)
+        .block_cache_size(0)
+        .build()
+        .unwrap();
+    let mut lsm_tree = LSMTree::<MockMemtable>::open(&tmp_dir, &options).unwrap();
+
+    // Enough data to be split in several level 1 tables.
+    let value = vec![7u8; 200];
+    for batch in 0..Options::default().l0_compaction_trigger as u32 {
+        let values: Vec<(Vec<u8>, Vec<u8>)> = (batch * 5_000..(batch + 1) * 5_000)
+            .map(|i| (i.to_be_bytes().to_vec(), value.clone()))
+            .collect();
+        add_sstable_to_tree(&mut lsm_tree, values);
+    }
+    lsm_tree.wait_for_threads();
+    let level_1 = level_paths(&lsm_tree, 1);
+    assert!(level_1.len() > 1, "{} tables in level 1", level_1.len());
+
+    let entries = lsm_tree.range(&(Bound::Unbounded, Bound::Unbounded), u64::MAX);
+    add_sstable_to_tree_and_merge(&mut lsm_tree, vec![(byte_vec!("fruita"), byte_vec!("poma"))]);
+    lsm_tree.wait_for_threads();
+    assert!(level_1.iter().all(|path| !Path::new(path).exists()));
+
+    let entries: Vec<merge_iterator::Entry> = entries.map(|entry| entry.unwrap()).collect();
+    assert_eq!(entries.len(), 4 * 5_000);
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_range_only_opens_tables_in_range() {
+    let (mut lsm_tree, tmp_dir) = create_lsm_tree_in_tmp_folder();
+
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("a"), byte_vec!("a"))]);
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("m"), byte_vec!("m"))]);
+    add_sstable_to_tree(&mut lsm_tree, vec![(byte_vec!("z"), byte_vec!("z"))]);
+    lsm_tree.wait_for_threads();
+    let level_0 = level_paths(&lsm_tree, 0);
+    assert_eq!(level_0.len(), 3);
+
+    // The tables outside the range can't be read, so the scan fails if it opens them.
+    fs::remove_file(&level_0[0]).expect("Remove table");
+    fs::remove_file(&level_0[2]).expect("Remove table");
+
+    let range = (Bound::Included(byte_vec!("b")), Bound::Excluded(byte_vec!("y")));
+    let entries: Vec<merge_iterator::Entry> =
+        lsm_tree.range(&range, u64::MAX).map(|entry| entry.unwrap()).collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, byte_vec!("m"));
+
+    std::mem::drop(lsm_tree);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
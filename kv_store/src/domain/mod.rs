@@ -1,86 +1,462 @@
+pub mod error;
 mod lsm_tree;
+pub mod options;
+pub mod snapshot;
+pub mod stats;
+mod wal;
+pub mod write_batch;
 
+use std::iter;
 use std::mem;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-// Value used to describe a deleted element. As this KVStore is made of multiple layers, where the
-// newest one overwrites the oldest one, deleting an element by removing it would not work
-// correctly, as it would simply continue searching an return an old value.
-// The solution is to use a random value we call TOOMBSTONE. Instead of deleting we set the key
-// value to TOOMBSTONE. Lower level structs just save it as a regular value, but the KVStore
-// returns None if it finds it in "get", and stops searching.
-pub const TOMBSTONE: [u8; 32] = [
-    179, 210, 155, 16, 110, 229, 104, 202, 72, 124, 209, 13, 85, 192, 56, 71, 239, 10, 116, 199,
-    186, 205, 163, 143, 3, 43, 125, 16, 157, 22, 47, 244,
-];
+use error::{Error, Result};
+use options::Options;
+use snapshot::Snapshot;
+use write_batch::WriteBatch;
 
-const MAX_MEMTABLE_SIZE: usize = 60 * 1024 * 1024;
+use lsm_tree::merge_iterator::{visible_at, EntryIterator, MergingIterator};
 
+type KeyValueIterator = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+// Value stored for a key in every layer of the store. As this KVStore is made of multiple layers,
+// where the newest one overwrites the oldest one, deleting an element by removing it would not
+// work correctly, as it would simply continue searching and return an old value.
+// The solution is to store a Tombstone instead. Lower level structs save it like any other value,
+// but the KVStore returns None if it finds it in "get", and stops searching.
+//
+// Data with a time to live is stored with the time when it expires. Once it is in the past the
+// value is read as deleted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Data(Vec<u8>),
+    Tombstone,
+    // `expires_at` is in milliseconds since the Unix epoch, as returned by `unix_millis`.
+    Expiring { data: Vec<u8>, expires_at: u64 },
+}
+
+impl Value {
+    // Data of the value at time `now`, None if it is deleted or expired.
+    pub fn into_data(self, now: u64) -> Option<Vec<u8>> {
+        match self {
+            Value::Data(data) => Some(data),
+            Value::Tombstone => None,
+            Value::Expiring { data, expires_at } if expires_at > now => Some(data),
+            Value::Expiring { .. } => None,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        matches!(self, Value::Expiring { expires_at, .. } if *expires_at <= now)
+    }
+}
+
+// Milliseconds since the Unix epoch, the unit of expiration times.
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+// Result of a conditional write. When it is not applied, it has the value the key had instead of
+// the expected one, None if the key didn't exist.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Swap {
+    Done,
+    Failed(Option<Vec<u8>>),
+}
+
+// Bytes used by a memtable entry besides its key and value: the Vec and Value structs, the
+// allocator bookkeeping and the space of the entry in the container.
+const MEMTABLE_ENTRY_OVERHEAD: usize = 64;
+
+// Every write has a sequence number, one bigger than the previous write. Memtables keep every
+// version of a key, as older ones may still be read through a snapshot.
 pub trait MemTable: 'static + Sync + Send + std::fmt::Debug {
     fn new() -> Self;
-    fn set(&mut self, key: Vec<u8>, value: Vec<u8>);
-    fn get(&self, key: &Vec<u8>) -> Option<&Vec<u8>>;
-    fn len(&self) -> usize;
-    fn sorted_entries(&self) -> Vec<(&Vec<u8>, &Vec<u8>)>;
+    // Adds a version of the key. Setting a version that is already there replaces its value.
+    fn set(&mut self, key: Vec<u8>, sequence: u64, value: Value);
+    // Newest version of the key written at or before `sequence`.
+    fn get(&self, key: &[u8], sequence: u64) -> Option<&Value>;
+    // Approximate bytes used by the entries, as given by entry_memory_usage. It has to be cheap,
+    // as it is checked on every write.
+    fn memory_usage(&self) -> usize;
+    // Every version, sorted by key and from newest to oldest for the same key.
+    fn sorted_entries(&self) -> Vec<(&Vec<u8>, u64, &Value)>;
+}
+
+// Memtables that can be written while other threads read them. The store only needs the
+// exclusive lock of the memtable to replace it, so reads don't wait for writes.
+pub trait ConcurrentMemTable: MemTable {
+    // Like `set`, through a shared reference.
+    fn insert(&self, key: Vec<u8>, sequence: u64, value: Value);
 }
 
-pub struct KVStore<T: MemTable> {
-    memtable: T,
+// Versions of a key in memtables that store them in a list, from newest to oldest. Only the
+// memtables used in tests do.
+#[cfg(test)]
+pub type Versions = Vec<(u64, Value)>;
+
+// Adds a version to the list, and returns the value it replaces if the version was already there.
+#[cfg(test)]
+pub fn add_version(versions: &mut Versions, sequence: u64, value: Value) -> Option<Value> {
+    let index = versions.partition_point(|(version, _)| *version > sequence);
+    match versions.get_mut(index) {
+        Some((version, old_value)) if *version == sequence => Some(mem::replace(old_value, value)),
+        _ => {
+            versions.insert(index, (sequence, value));
+            None
+        }
+    }
+}
+
+// Newest version in the list written at or before `sequence`.
+#[cfg(test)]
+pub fn find_version(versions: &Versions, sequence: u64) -> Option<&Value> {
+    versions
+        .iter()
+        .find(|(version, _)| *version <= sequence)
+        .map(|(_, value)| value)
+}
+
+// Approximate bytes used by an entry in a memtable, used to decide when it is saved to disk.
+// Memtables keep the sum for all their entries, so it is computed from the key size and the
+// value: when a version is replaced, the usage of the old entry is subtracted.
+pub fn entry_memory_usage(key_size: usize, value: &Value) -> usize {
+    let value_size = match value {
+        Value::Data(data) => data.len(),
+        Value::Tombstone => 0,
+        Value::Expiring { data, .. } => data.len(),
+    };
+    MEMTABLE_ENTRY_OVERHEAD + key_size + value_size
+}
+
+// Every method takes `&self`, so the store can be shared between threads. Writes are applied one
+// at a time, while reads run in parallel with them and with each other.
+pub struct KVStore<T: ConcurrentMemTable> {
+    // Readers and writers share the lock, as entries are inserted through a shared reference. It
+    // is only taken exclusively to replace the memtable when it is saved.
+    memtable: RwLock<T>,
+    // Held during the whole write, so writes are logged and applied in the same order.
+    wal: Mutex<wal::WriteAheadLog>,
     lsm_tree: lsm_tree::LSMTree<T>,
+    options: Options,
+    // Sequence number of the last write. It is updated once all the entries of the write are in
+    // the memtable, so reads never see part of a batch.
+    last_sequence: AtomicU64,
+    closed: AtomicBool,
 }
 
-impl<T: MemTable> KVStore<T> {
-    pub fn new(dir: &str) -> KVStore<T> {
-        KVStore {
-            memtable: T::new(),
-            lsm_tree: lsm_tree::LSMTree::new(dir),
+impl<T: ConcurrentMemTable> KVStore<T> {
+    pub fn open(dir: &str, options: Options) -> Result<KVStore<T>> {
+        // The LSMTree creates the directory if needed, so it has to be created before the log.
+        let lsm_tree = lsm_tree::LSMTree::open(dir, &options)?;
+        let mut memtable = T::new();
+        let (wal, wal_last_sequence) = wal::WriteAheadLog::open(dir, options.sync, &mut memtable)?;
+        let last_sequence = lsm_tree.last_sequence().max(wal_last_sequence);
+
+        Ok(KVStore {
+            memtable: RwLock::new(memtable),
+            wal: Mutex::new(wal),
+            lsm_tree,
+            options,
+            last_sequence: AtomicU64::new(last_sequence),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set(key, value);
+        self.write(batch)
+    }
+
+    // The key is read as deleted once `ttl` has passed.
+    pub fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.set_with_ttl(key, value, ttl);
+        self.write(batch)
+    }
+
+    // Applies every write of the batch, or none if it fails. They are logged as a single record,
+    // so after a crash they are recovered together or not at all.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        self.check_batch(&batch)?;
+        self.write_locked(&mut wal, batch)
+    }
+
+    // Applies a batch already checked, with the lock of the log held.
+    fn write_locked(&self, wal: &mut wal::WriteAheadLog, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // The memtable is saved before the write that would make it too big, so an error saving
+        // it fails the write instead of leaving it half done. The whole batch goes to the next
+        // memtable, even if it makes it bigger than the limit.
+        let memory_usage = self.memtable.read().unwrap().memory_usage();
+        if memory_usage >= self.options.memtable_size {
+            self.save_memtable_locked(wal)?;
         }
+
+        // Writes of the batch get consecutive sequence numbers.
+        let first_sequence = self.last_sequence.load(Ordering::SeqCst) + 1;
+        wal.append(first_sequence, &batch)?;
+        let mut last_sequence = first_sequence;
+        {
+            let memtable = self.memtable.read().unwrap();
+            for (sequence, (key, value)) in (first_sequence..).zip(batch.entries) {
+                memtable.insert(key, sequence, value);
+                last_sequence = sequence;
+            }
+        }
+        self.last_sequence.store(last_sequence, Ordering::SeqCst);
+        Ok(())
     }
 
-    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        self.memtable.set(key, value);
+    // Writes `new`, or deletes the key if it is None, only if its current value is `expected`,
+    // where None means that the key doesn't exist. The lock of the log is held from the read of
+    // the current value to the write, so no other write can happen in between.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Swap> {
+        let mut batch = WriteBatch::new();
+        match new {
+            Some(value) => batch.set(key, value),
+            None => batch.delete(key),
+        }
 
-        if self.memtable.len() > MAX_MEMTABLE_SIZE / 60{
-            self.save_memtable();
+        let mut wal = self.wal.lock().unwrap();
+        // Invalid writes fail even if the current value is not the expected one.
+        self.check_batch(&batch)?;
+
+        let current = self.get(key, None)?;
+        if current.as_deref() != expected {
+            return Ok(Swap::Failed(current));
         }
+        self.write_locked(&mut wal, batch)?;
+        Ok(Swap::Done)
     }
 
-    pub fn get(&self, key: &Vec<u8>) -> Option<Vec<u8>> {
-        match self.memtable.get(key) {
-            Some(v) => {
-                if v[..] == TOMBSTONE {
-                    None
-                } else {
-                    Some(v.to_vec())
-                }
+    pub fn set_if_absent(&self, key: &[u8], value: Vec<u8>) -> Result<Swap> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    fn check_batch(&self, batch: &WriteBatch) -> Result<()> {
+        for (key, value) in &batch.entries {
+            self.check_entry_size(key, value)?;
+        }
+        self.check_writable()
+    }
+
+    fn check_entry_size(&self, key: &[u8], value: &Value) -> Result<()> {
+        if key.len() > self.options.max_key_size {
+            return Err(Error::InvalidArgument(format!(
+                "Key of {} bytes is bigger than the maximum of {} bytes",
+                key.len(),
+                self.options.max_key_size
+            )));
+        }
+        if let Value::Data(data) | Value::Expiring { data, .. } = value {
+            if data.len() > self.options.max_value_size {
+                return Err(Error::InvalidArgument(format!(
+                    "Value of {} bytes is bigger than the maximum of {} bytes",
+                    data.len(),
+                    self.options.max_value_size
+                )));
             }
-            None => match self.lsm_tree.get(key) {
-                Some(v) => {
-                    if v[..] == TOMBSTONE {
-                        None
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        match self.lsm_tree.background_error() {
+            Some(message) => Err(Error::Background(message)),
+            None => Ok(()),
+        }
+    }
+
+    // Takes a snapshot of the current state of the store. Reads through it don't see the writes
+    // made afterwards, and the versions it needs are kept until it is dropped.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        Ok(self.lsm_tree.snapshots().acquire(&self.last_sequence))
+    }
+
+    // Sequence number that reads see: the one of the snapshot, or the last write without one.
+    fn read_sequence(&self, snapshot: Option<&Snapshot>) -> Result<u64> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        match snapshot {
+            None => Ok(self.last_sequence.load(Ordering::SeqCst)),
+            Some(snapshot) if snapshot.belongs_to(self.lsm_tree.snapshots()) => {
+                Ok(snapshot.sequence())
+            }
+            Some(_) => Err(Error::InvalidArgument(String::from(
+                "Snapshot of another store",
+            ))),
+        }
+    }
+
+    pub fn get(&self, key: &[u8], snapshot: Option<&Snapshot>) -> Result<Option<Vec<u8>>> {
+        let sequence = self.read_sequence(snapshot)?;
+        let now = unix_millis(SystemTime::now());
+
+        // The memtable is read before the lsm tree. A memtable being replaced is given to the
+        // lsm tree with the lock held, so it is always found in one of them.
+        let memtable_value = self.memtable.read().unwrap().get(key, sequence).cloned();
+        match memtable_value {
+            Some(v) => Ok(v.into_data(now)),
+            None => Ok(self
+                .lsm_tree
+                .get(key, sequence)?
+                .and_then(|value| value.into_data(now))),
+        }
+    }
+
+    // Iterates the entries with keys in the range, in key order. It merges the memtable with the
+    // lsm tree, so only the newest value of every key is returned, and deleted keys are skipped.
+    //
+    // The iterator doesn't borrow the store. Memtable entries are copied when it is created, and
+    // changes made after that are not returned.
+    pub fn range(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        snapshot: Option<&Snapshot>,
+    ) -> KeyValueIterator {
+        let sequence = match self.read_sequence(snapshot) {
+            Ok(sequence) => sequence,
+            Err(e) => return Box::new(iter::once(Err(e))),
+        };
+
+        let memtable_entries: Vec<_> = self
+            .memtable
+            .read()
+            .unwrap()
+            .sorted_entries()
+            .into_iter()
+            .filter(|(key, entry_sequence, _)| range.contains(*key) && *entry_sequence <= sequence)
+            .map(|(key, entry_sequence, value)| Ok((key.clone(), entry_sequence, value.clone())))
+            .collect();
+
+        let sources: Vec<EntryIterator> = vec![
+            self.lsm_tree.range(&range, sequence),
+            Box::new(memtable_entries.into_iter()),
+        ];
+
+        // Values that expire while iterating are still returned.
+        let now = unix_millis(SystemTime::now());
+        let mut visible = visible_at(sequence);
+        Box::new(
+            MergingIterator::new(sources).filter_map(move |entry| match entry {
+                Ok((key, entry_sequence, value)) => {
+                    if visible(&key, entry_sequence) {
+                        value.into_data(now).map(|data| Ok((key, data)))
                     } else {
-                        Some(v)
+                        None
                     }
                 }
-                None => None,
-            },
-        }
+                Err(e) => Some(Err(Error::from(e))),
+            }),
+        )
+    }
+
+    // Iterates the entries with keys starting with `prefix`, in key order.
+    pub fn scan_prefix(&self, prefix: &[u8], snapshot: Option<&Snapshot>) -> KeyValueIterator {
+        let end = match prefix_successor(prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+        self.range((Bound::Included(prefix.to_vec()), end), snapshot)
     }
 
-    pub fn delete(&mut self, key: &Vec<u8>) {
-        self.set(key.to_vec(), TOMBSTONE.to_vec())
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
     }
 
-    pub fn save_memtable(&mut self) {
-        let memtable = mem::replace(&mut self.memtable, T::new());
+    pub fn stats(&self) -> stats::Stats {
+        self.lsm_tree.stats()
+    }
+
+    pub fn pause_compactions(&self) {
+        self.lsm_tree.pause_compactions();
+    }
 
-        self.lsm_tree.save_memtable(memtable);
+    pub fn resume_compactions(&self) {
+        self.lsm_tree.resume_compactions();
+    }
+
+    // Starts saving the memtable in the background. If too many memtables are waiting to be
+    // saved, it first waits for the oldest one. It fails if saving a previous one failed, and in
+    // that case the current memtable is kept.
+    pub fn save_memtable(&self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        self.save_memtable_locked(&mut wal)
+    }
+
+    fn save_memtable_locked(&self, wal: &mut wal::WriteAheadLog) -> Result<()> {
+        self.check_writable()?;
+        // Waiting here, and not with the memtable locked, lets reads go on during a write stall.
+        self.lsm_tree.wait_for_room();
+        self.check_writable()?;
+
+        let wal_paths = wal.rotate()?;
+        // Readers look for the entries of the memtable in the lsm tree as soon as it is replaced.
+        let mut memtable = self.memtable.write().unwrap();
+        let old_memtable = mem::replace(&mut *memtable, T::new());
+        self.lsm_tree.save_memtable(old_memtable, wal_paths);
+        Ok(())
+    }
+
+    // Saves the memtable and waits until it is on disk, and for the compactions that aren't
+    // paused. After closing, every call fails with Error::Closed.
+    pub fn close(&self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let result = self.save_memtable_locked(&mut wal).and_then(|()| {
+            self.lsm_tree.wait_for_threads();
+            self.check_writable()
+        });
+        self.closed.store(true, Ordering::SeqCst);
+        result
     }
 }
 
-impl<T: MemTable> Drop for KVStore<T> {
+impl<T: ConcurrentMemTable> Drop for KVStore<T> {
     fn drop(&mut self) {
-        self.save_memtable();
+        if *self.closed.get_mut() {
+            return;
+        }
+        if let Err(e) = self.close() {
+            println!("Error closing the store: {}", e);
+        }
+    }
+}
+
+// Smallest key bigger than all the keys starting with `prefix`. None if there is no such key,
+// which happens when the prefix is empty or made only of 0xff bytes.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut successor = prefix.to_vec();
+    while let Some(last) = successor.pop() {
+        if last < u8::MAX {
+            successor.push(last + 1);
+            return Some(successor);
+        }
     }
+    None
 }
 
 #[cfg(test)]
@@ -93,51 +469,112 @@ pub(crate) mod memtable_trait_tests {
         };
     }
 
+    macro_rules! data {
+        ($a: expr) => {
+            Value::Data(String::from($a).into_bytes())
+        };
+    }
+
     pub fn test_basic<T: MemTable>(mut memtable: T) {
-        memtable.set(byte_vec!("a"), byte_vec!("mandarina"));
-        memtable.set(byte_vec!("b"), byte_vec!("platan"));
+        memtable.set(byte_vec!("a"), 1, data!("mandarina"));
+        memtable.set(byte_vec!("b"), 2, data!("platan"));
 
-        assert_eq!(memtable.get(&byte_vec!("a")), Some(&byte_vec!("mandarina")));
-        assert_eq!(memtable.get(&byte_vec!("b")), Some(&byte_vec!("platan")));
-        assert_eq!(memtable.get(&byte_vec!("c")), None);
+        assert_eq!(
+            memtable.get(&byte_vec!("a"), u64::MAX),
+            Some(&data!("mandarina"))
+        );
+        assert_eq!(
+            memtable.get(&byte_vec!("b"), u64::MAX),
+            Some(&data!("platan"))
+        );
+        assert_eq!(memtable.get(&byte_vec!("c"), u64::MAX), None);
     }
 
     pub fn test_insert_same_key<T: MemTable>(mut memtable: T) {
         // It should return the last element added with a given key
 
-        memtable.set(byte_vec!("a"), byte_vec!("mandarina"));
-        assert_eq!(memtable.get(&byte_vec!("a")), Some(&byte_vec!("mandarina")));
+        memtable.set(byte_vec!("a"), 1, data!("mandarina"));
+        assert_eq!(
+            memtable.get(&byte_vec!("a"), u64::MAX),
+            Some(&data!("mandarina"))
+        );
+
+        memtable.set(byte_vec!("a"), 2, data!("platan"));
+        assert_eq!(
+            memtable.get(&byte_vec!("a"), u64::MAX),
+            Some(&data!("platan"))
+        );
+
+        memtable.set(byte_vec!("a"), 3, data!("ana"));
+        assert_eq!(memtable.get(&byte_vec!("a"), u64::MAX), Some(&data!("ana")));
 
-        memtable.set(byte_vec!("a"), byte_vec!("platan"));
-        assert_eq!(memtable.get(&byte_vec!("a")), Some(&byte_vec!("platan")));
+        memtable.set(byte_vec!("a"), 4, data!("zzz"));
+        assert_eq!(memtable.get(&byte_vec!("a"), u64::MAX), Some(&data!("zzz")));
 
-        memtable.set(byte_vec!("a"), byte_vec!("ana"));
-        assert_eq!(memtable.get(&byte_vec!("a")), Some(&byte_vec!("ana")));
+        // Older versions are still there for older sequence numbers.
+        assert_eq!(memtable.get(&byte_vec!("a"), 0), None);
+        assert_eq!(memtable.get(&byte_vec!("a"), 1), Some(&data!("mandarina")));
+        assert_eq!(memtable.get(&byte_vec!("a"), 3), Some(&data!("ana")));
 
-        memtable.set(byte_vec!("a"), byte_vec!("zzz"));
-        assert_eq!(memtable.get(&byte_vec!("a")), Some(&byte_vec!("zzz")));
+        // Without sequence numbers, the last value replaces the previous one.
+        memtable.set(byte_vec!("b"), 0, data!("poma"));
+        memtable.set(byte_vec!("b"), 0, data!("pera"));
+        assert_eq!(memtable.get(&byte_vec!("b"), 0), Some(&data!("pera")));
+    }
+
+    pub fn test_memory_usage<T: MemTable>(mut memtable: T) {
+        assert_eq!(memtable.memory_usage(), 0);
+
+        memtable.set(byte_vec!("a"), 1, data!("mandarina"));
+        memtable.set(byte_vec!("b"), 2, data!("platan"));
+        assert_eq!(
+            memtable.memory_usage(),
+            entry_memory_usage(1, &data!("mandarina")) + entry_memory_usage(1, &data!("platan"))
+        );
+
+        // Every version counts.
+        memtable.set(byte_vec!("a"), 3, data!("poma"));
+        assert_eq!(
+            memtable.memory_usage(),
+            entry_memory_usage(1, &data!("mandarina"))
+                + entry_memory_usage(1, &data!("platan"))
+                + entry_memory_usage(1, &data!("poma"))
+        );
+
+        // Replaced values don't.
+        memtable.set(byte_vec!("c"), 0, data!("pera"));
+        memtable.set(byte_vec!("c"), 0, Value::Tombstone);
+        assert_eq!(
+            memtable.memory_usage(),
+            entry_memory_usage(1, &data!("mandarina"))
+                + entry_memory_usage(1, &data!("platan"))
+                + entry_memory_usage(1, &data!("poma"))
+                + entry_memory_usage(1, &Value::Tombstone)
+        );
     }
 
     pub fn test_sorted_entries<T: MemTable>(mut memtable: T) {
-        memtable.set(byte_vec!("a"), byte_vec!("mandarina"));
-        memtable.set(byte_vec!("a"), TOMBSTONE.to_vec());
+        memtable.set(byte_vec!("a"), 1, data!("mandarina"));
+        memtable.set(byte_vec!("a"), 2, Value::Tombstone);
 
-        memtable.set(byte_vec!("b"), byte_vec!("yyyy"));
-        memtable.set(byte_vec!("b"), byte_vec!("zzzz"));
-        memtable.set(byte_vec!("d"), byte_vec!("ana"));
-        memtable.set(vec![1, 2, 3], byte_vec!("3 numeros"));
-        memtable.set(vec![2, 3], byte_vec!("2 numeros"));
-        memtable.set(vec![99, 3], byte_vec!("la c"));
+        memtable.set(byte_vec!("b"), 3, data!("yyyy"));
+        memtable.set(byte_vec!("b"), 4, data!("zzzz"));
+        memtable.set(byte_vec!("d"), 5, data!("ana"));
+        memtable.set(vec![1, 2, 3], 6, data!("3 numeros"));
+        memtable.set(vec![2, 3], 7, data!("2 numeros"));
+        memtable.set(vec![99, 3], 8, data!("la c"));
 
         assert_eq!(
             memtable.sorted_entries(),
             vec![
-                (&vec![1, 2, 3], &byte_vec!("3 numeros")),
-                (&vec![2, 3], &byte_vec!("2 numeros")),
-                (&byte_vec!("a"), &TOMBSTONE.to_vec()),
-                (&byte_vec!("b"), &byte_vec!("zzzz")),
-                (&vec![99, 3], &byte_vec!("la c")),
-                (&byte_vec!("d"), &byte_vec!("ana")),
+                (&vec![1, 2, 3], 6, &data!("3 numeros")),
+                (&vec![2, 3], 7, &data!("2 numeros")),
+                (&byte_vec!("a"), 2, &Value::Tombstone),
+                (&byte_vec!("a"), 1, &data!("mandarina")),
+                (&byte_vec!("b"), 4, &data!("zzzz")),
+                (&byte_vec!("b"), 3, &data!("yyyy")),
+                (&vec![99, 3], 8, &data!("la c")),
+                (&byte_vec!("d"), 5, &data!("ana")),
             ]
         );
     }
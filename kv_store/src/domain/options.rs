@@ -0,0 +1,309 @@
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use super::error::{Error, Result};
+
+// Name of the file, inside the data directory, that `Options::load` reads.
+pub const OPTIONS_FILE_NAME: &str = "OPTIONS";
+
+// When the write-ahead log is synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    // The operating system decides when the log reaches the disk. A crash of the machine (but not
+    // of the process) can lose the last writes.
+    Never,
+    // Every write is synced before returning. Much slower, but no acknowledged write is lost.
+    Always,
+}
+
+// Compression of the sstable blocks. Each block records how it was compressed, so changing it
+// only affects new sstables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+// Configuration of a store, given when it is opened. It is created with `Options::builder`,
+// `Options::load` or `Options::default`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub(crate) memtable_size: usize,
+    pub(crate) max_immutable_memtables: usize,
+    pub(crate) l0_compaction_trigger: usize,
+    pub(crate) level_size_ratio: usize,
+    pub(crate) compaction_threads: usize,
+    pub(crate) idle_compaction_delay: Duration,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) block_cache_size: usize,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) bloom_bits_per_key: usize,
+    pub(crate) compression: Compression,
+    pub(crate) max_key_size: usize,
+    pub(crate) max_value_size: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            memtable_size: 60 * 1024 * 1024,
+            max_immutable_memtables: 2,
+            l0_compaction_trigger: 4,
+            level_size_ratio: 10,
+            compaction_threads: 2,
+            idle_compaction_delay: Duration::from_secs(30),
+            read_buffer_size: 20 * 1024 * 1024,
+            block_cache_size: 8 * 1024 * 1024,
+            sync: SyncPolicy::Never,
+            bloom_bits_per_key: 10,
+            compression: Compression::Lz4,
+            max_key_size: 64 * 1024,
+            max_value_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Options {
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder {
+            options: Options::default(),
+        }
+    }
+
+    // Reads the options file of the data directory, if there is one. Options missing from the
+    // file keep their default value.
+    pub fn load(dir: &str) -> Result<Options> {
+        let path = format!("{}/{}", dir, OPTIONS_FILE_NAME);
+        match fs::read_to_string(&path) {
+            Ok(contents) => Options::builder().parse(&contents)?.build(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Options::default()),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+}
+
+pub struct OptionsBuilder {
+    options: Options,
+}
+
+impl OptionsBuilder {
+    // Approximate size of the memtable, in bytes, at which it is saved to disk as an sstable.
+    // 60MB by default.
+    pub fn memtable_size(mut self, bytes: usize) -> Self {
+        self.options.memtable_size = bytes;
+        self
+    }
+
+    // Number of full memtables that can wait to be saved to disk. Reads check all of them, and
+    // when there are this many, writes wait until the oldest one is saved. 2 by default.
+    pub fn max_immutable_memtables(mut self, memtables: usize) -> Self {
+        self.options.max_immutable_memtables = memtables;
+        self
+    }
+
+    // Number of sstables saved from memtables after which they are compacted into the next
+    // level. 4 by default.
+    pub fn l0_compaction_trigger(mut self, tables: usize) -> Self {
+        self.options.l0_compaction_trigger = tables;
+        self
+    }
+
+    // Each level below the first one can hold this many times more bytes than the previous one
+    // before it is compacted into the next. Bigger ratios mean less levels but more rewriting on
+    // each compaction. 10 by default.
+    pub fn level_size_ratio(mut self, ratio: usize) -> Self {
+        self.options.level_size_ratio = ratio;
+        self
+    }
+
+    // Number of background threads running compactions. Compactions that use different levels
+    // run at the same time. 2 by default.
+    pub fn compaction_threads(mut self, threads: usize) -> Self {
+        self.options.compaction_threads = threads;
+        self
+    }
+
+    // Time without new sstables after which the sstables saved from memtables are compacted,
+    // even if there are less than `l0_compaction_trigger`, so reads have less tables to check.
+    // Duration::ZERO disables it. 30 seconds by default.
+    pub fn idle_compaction_delay(mut self, delay: Duration) -> Self {
+        self.options.idle_compaction_delay = delay;
+        self
+    }
+
+    // Size of the buffer used to read sstables sequentially, in bytes. 20MB by default.
+    pub fn read_buffer_size(mut self, bytes: usize) -> Self {
+        self.options.read_buffer_size = bytes;
+        self
+    }
+
+    // Memory budget of the cache of sstable blocks, in bytes. 0 disables the cache. 8MB by
+    // default.
+    pub fn block_cache_size(mut self, bytes: usize) -> Self {
+        self.options.block_cache_size = bytes;
+        self
+    }
+
+    // SyncPolicy::Never by default.
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.options.sync = sync;
+        self
+    }
+
+    // Bits of bloom filter per key in new sstables. More bits means less reads of sstables that
+    // don't contain the key, at the cost of memory. 0 disables the filters. 10 by default.
+    pub fn bloom_bits_per_key(mut self, bits_per_key: usize) -> Self {
+        self.options.bloom_bits_per_key = bits_per_key;
+        self
+    }
+
+    // Compression::Lz4 by default.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    // Maximum key size in bytes. 64kB by default.
+    pub fn max_key_size(mut self, bytes: usize) -> Self {
+        self.options.max_key_size = bytes;
+        self
+    }
+
+    // Maximum value size in bytes. 64MB by default.
+    pub fn max_value_size(mut self, bytes: usize) -> Self {
+        self.options.max_value_size = bytes;
+        self
+    }
+
+    // Applies the options of a config file. Each line is "name = value", with the name of one of
+    // the builder methods. Durations are in milliseconds. Empty lines and lines starting with #
+    // are ignored.
+    pub fn parse(mut self, contents: &str) -> Result<Self> {
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || Error::InvalidArgument(format!("Invalid options line: {}", line));
+            let (name, value) = line.split_once('=').ok_or_else(invalid)?;
+            let value = value.trim();
+            let number = || value.parse::<usize>().map_err(|_| invalid());
+
+            self = match name.trim() {
+                "memtable_size" => self.memtable_size(number()?),
+                "max_immutable_memtables" => self.max_immutable_memtables(number()?),
+                "l0_compaction_trigger" => self.l0_compaction_trigger(number()?),
+                "level_size_ratio" => self.level_size_ratio(number()?),
+                "compaction_threads" => self.compaction_threads(number()?),
+                "idle_compaction_delay" => {
+                    self.idle_compaction_delay(Duration::from_millis(number()? as u64))
+                }
+                "read_buffer_size" => self.read_buffer_size(number()?),
+                "block_cache_size" => self.block_cache_size(number()?),
+                "sync" => match value {
+                    "never" => self.sync(SyncPolicy::Never),
+                    "always" => self.sync(SyncPolicy::Always),
+                    _ => return Err(invalid()),
+                },
+                "bloom_bits_per_key" => self.bloom_bits_per_key(number()?),
+                "compression" => match value {
+                    "none" => self.compression(Compression::None),
+                    "lz4" => self.compression(Compression::Lz4),
+                    _ => return Err(invalid()),
+                },
+                "max_key_size" => self.max_key_size(number()?),
+                "max_value_size" => self.max_value_size(number()?),
+                _ => return Err(invalid()),
+            };
+        }
+        Ok(self)
+    }
+
+    pub fn build(self) -> Result<Options> {
+        let options = self.options;
+        if options.memtable_size == 0 {
+            return Err(Error::InvalidArgument(String::from(
+                "memtable_size must be bigger than 0",
+            )));
+        }
+        if options.max_immutable_memtables == 0 {
+            return Err(Error::InvalidArgument(String::from(
+                "max_immutable_memtables must be bigger than 0",
+            )));
+        }
+        if options.l0_compaction_trigger == 0 {
+            return Err(Error::InvalidArgument(String::from(
+                "l0_compaction_trigger must be bigger than 0",
+            )));
+        }
+        if options.level_size_ratio < 2 {
+            return Err(Error::InvalidArgument(String::from(
+                "level_size_ratio must be at least 2",
+            )));
+        }
+        if options.compaction_threads == 0 {
+            return Err(Error::InvalidArgument(String::from(
+                "compaction_threads must be bigger than 0",
+            )));
+        }
+        if options.read_buffer_size == 0 {
+            return Err(Error::InvalidArgument(String::from(
+                "read_buffer_size must be bigger than 0",
+            )));
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let options = Options::builder()
+            .max_key_size(10)
+            .parse(
+                "# Small tables for tests\n\
+                 memtable_size = 1024\n\
+                 \n\
+                 l0_compaction_trigger=2\n\
+                 idle_compaction_delay = 500\n\
+                 sync = always\n\
+                 compression = none\n",
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            options,
+            Options {
+                memtable_size: 1024,
+                l0_compaction_trigger: 2,
+                idle_compaction_delay: Duration::from_millis(500),
+                sync: SyncPolicy::Always,
+                compression: Compression::None,
+                max_key_size: 10,
+                ..Options::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert!(Options::builder().parse("memtable_size 10").is_err());
+        assert!(Options::builder().parse("memtable_size = ten").is_err());
+        assert!(Options::builder().parse("sync = sometimes").is_err());
+        assert!(Options::builder().parse("cache_size = 10").is_err());
+        assert!(Options::builder().l0_compaction_trigger(0).build().is_err());
+        assert!(Options::builder()
+            .max_immutable_memtables(0)
+            .build()
+            .is_err());
+        assert!(Options::builder().level_size_ratio(1).build().is_err());
+        assert!(Options::builder().compaction_threads(0).build().is_err());
+    }
+}
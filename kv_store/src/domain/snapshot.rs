@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Sequence numbers of the live snapshots of a store, with how many snapshots there are at each
+// one. It is shared with the background thread, which keeps every version that one of them can
+// read when it writes sstables.
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    sequences: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl Snapshots {
+    // Takes a snapshot at the current value of `last_sequence`. It is read while the snapshots
+    // are locked, so a flush or compaction that checks the oldest snapshot either sees the new one
+    // or runs before it, when the snapshot only needs the newest versions. The snapshot is live
+    // until it is dropped.
+    pub fn acquire(self: &Arc<Self>, last_sequence: &AtomicU64) -> Snapshot {
+        let mut sequences = self.sequences.lock().unwrap();
+        let sequence = last_sequence.load(Ordering::SeqCst);
+        *sequences.entry(sequence).or_insert(0) += 1;
+        Snapshot {
+            sequence,
+            snapshots: self.clone(),
+        }
+    }
+
+    // Sequence number of the oldest live snapshot, None if there are none.
+    pub fn oldest(&self) -> Option<u64> {
+        let sequences = self.sequences.lock().unwrap();
+        sequences.keys().next().copied()
+    }
+
+    fn release(&self, sequence: u64) {
+        let mut sequences = self.sequences.lock().unwrap();
+        if let Some(count) = sequences.get_mut(&sequence) {
+            *count -= 1;
+            if *count == 0 {
+                sequences.remove(&sequence);
+            }
+        }
+    }
+}
+
+// Reads through a snapshot see the store as it was after the write with its sequence number.
+pub struct Snapshot {
+    sequence: u64,
+    snapshots: Arc<Snapshots>,
+}
+
+impl Snapshot {
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    // False if the snapshot was taken from another store.
+    pub fn belongs_to(&self, snapshots: &Arc<Snapshots>) -> bool {
+        Arc::ptr_eq(&self.snapshots, snapshots)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.snapshots.release(self.sequence);
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("sequence", &self.sequence)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oldest_snapshot() {
+        let snapshots = Arc::new(Snapshots::default());
+        assert_eq!(snapshots.oldest(), None);
+
+        let last_sequence = AtomicU64::new(5);
+        let first = snapshots.acquire(&last_sequence);
+        let second = snapshots.acquire(&last_sequence);
+        last_sequence.store(8, Ordering::SeqCst);
+        let third = snapshots.acquire(&last_sequence);
+        assert_eq!(snapshots.oldest(), Some(5));
+
+        drop(first);
+        assert_eq!(snapshots.oldest(), Some(5));
+        drop(second);
+        assert_eq!(snapshots.oldest(), Some(8));
+        drop(third);
+        assert_eq!(snapshots.oldest(), None);
+    }
+}
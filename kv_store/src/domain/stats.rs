@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Counters updated by the store while it works. They are shared with the background threads, so
+// they are atomic.
+#[derive(Debug, Default)]
+pub struct StatsCounters {
+    pub bloom_filter_checks: AtomicU64,
+    pub bloom_filter_useful: AtomicU64,
+    pub block_cache_hits: AtomicU64,
+    pub block_cache_misses: AtomicU64,
+    pub write_stalls: AtomicU64,
+    pub compactions: AtomicU64,
+    pub dropped_tombstones: AtomicU64,
+    pub dropped_versions: AtomicU64,
+}
+
+impl StatsCounters {
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            bloom_filter_checks: self.bloom_filter_checks.load(Ordering::Relaxed),
+            bloom_filter_useful: self.bloom_filter_useful.load(Ordering::Relaxed),
+            block_cache_hits: self.block_cache_hits.load(Ordering::Relaxed),
+            block_cache_misses: self.block_cache_misses.load(Ordering::Relaxed),
+            write_stalls: self.write_stalls.load(Ordering::Relaxed),
+            compactions: self.compactions.load(Ordering::Relaxed),
+            dropped_tombstones: self.dropped_tombstones.load(Ordering::Relaxed),
+            dropped_versions: self.dropped_versions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Copy of the store counters at some point in time.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    // Number of times a bloom filter was checked before reading an sstable.
+    pub bloom_filter_checks: u64,
+    // Number of checks where the filter ruled out the sstable, so it didn't have to be read.
+    pub bloom_filter_useful: u64,
+    // Number of sstable blocks found in the block cache.
+    pub block_cache_hits: u64,
+    // Number of sstable blocks that had to be read from disk.
+    pub block_cache_misses: u64,
+    // Number of times a write had to wait because too many memtables were waiting to be saved.
+    pub write_stalls: u64,
+    // Number of compactions finished by the background threads.
+    pub compactions: u64,
+    // Number of tombstones, including expired values, removed by compactions because there was
+    // nothing older left for them to hide.
+    pub dropped_tombstones: u64,
+    // Number of versions removed by compactions because a newer version replaced them and no
+    // snapshot could read them.
+    pub dropped_versions: u64,
+}
@@ -0,0 +1,271 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use crate::domain::lsm_tree::encoding::{self, FormatVersion};
+use crate::domain::lsm_tree::merge_iterator::Entry;
+use crate::domain::options::SyncPolicy;
+use crate::domain::write_batch::WriteBatch;
+use crate::domain::MemTable;
+
+const WAL_EXTENSION: &str = "wal";
+
+// Written at the start of every log.
+const WAL_MAGIC: [u8; 8] = [0x3a, 0x71, 0x9c, 0x05, 0xe2, 0x4b, 0xd8, 0x19];
+
+// Append-only log of every write applied to the memtable. Each memtable has one (or, after a
+// recovery, several) log files associated. When the memtable is persisted as an sstable, its log
+// files are no longer needed and can be removed.
+//
+// Entries are stored with the same encoding used by sstables, after a small header. The entries of
+// each write batch are stored together as a single size prefixed record. A crash can leave the
+// last record half written, so replaying stops at the first incomplete one, and a batch is
+// replayed entirely or not at all.
+pub struct WriteAheadLog {
+    dir: String,
+    current_index: u32,
+    current_path: String,
+    file: File,
+    // Bytes of the complete records in the current log.
+    len: u64,
+    sync: SyncPolicy,
+
+    // Logs found on startup. Their entries have been replayed into the current memtable, so they
+    // must be kept until the memtable is saved.
+    replayed_paths: Vec<String>,
+}
+
+impl WriteAheadLog {
+    // Replays every log found in `dir` into `memtable`, oldest first, and creates a new log file
+    // for the following writes. Returns the log and the biggest sequence number replayed.
+    pub fn open<T: MemTable>(
+        dir: &str,
+        sync: SyncPolicy,
+        memtable: &mut T,
+    ) -> io::Result<(Self, u64)> {
+        let mut logs: Vec<(u32, String)> = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == WAL_EXTENSION) {
+                // Files that don't have the name of a log were not written by the store.
+                match (log_index(&path), path.to_str()) {
+                    (Some(index), Some(name)) => logs.push((index, name.to_owned())),
+                    _ => println!("Ignoring file that is not a log: {}", path.display()),
+                }
+            }
+        }
+        logs.sort_unstable();
+
+        let mut last_sequence = 0;
+        for (_, path) in &logs {
+            println!("Replaying write-ahead log: {}", path);
+            last_sequence = last_sequence.max(replay(path, memtable)?);
+        }
+
+        let current_index = logs.last().map_or(0, |(index, _)| index + 1);
+        let current_path = log_path(dir, current_index);
+        let file = create_log_file(&current_path)?;
+        let len = file.metadata()?.len();
+
+        let wal = WriteAheadLog {
+            dir: String::from(dir),
+            current_index,
+            current_path,
+            file,
+            len,
+            sync,
+            replayed_paths: logs.into_iter().map(|(_, path)| path).collect(),
+        };
+        Ok((wal, last_sequence))
+    }
+
+    // Writes of the batch get consecutive sequence numbers, starting at `first_sequence`. If it
+    // fails, the log is truncated to the records that were complete, as the next record would be
+    // appended to a partial one otherwise, making the rest of the log unreadable.
+    pub fn append(&mut self, first_sequence: u64, batch: &WriteBatch) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for (sequence, (key, value)) in (first_sequence..).zip(&batch.entries) {
+            entries.extend_from_slice(&encoding::serialize_entry(key, sequence, value));
+        }
+        // A single write per batch, so a crash can only cut the last one.
+        let record = encoding::serialize_datum(&entries);
+        let result = self.file.write_all(&record).and_then(|()| match self.sync {
+            SyncPolicy::Never => Ok(()),
+            SyncPolicy::Always => self.file.sync_data(),
+        });
+        match result {
+            Ok(()) => {
+                self.len += record.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                if let Err(truncate_error) = self.file.set_len(self.len) {
+                    println!(
+                        "Could not truncate the write-ahead log: {:?}",
+                        truncate_error
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Starts a new log file and returns the paths of the logs holding the entries of the
+    // memtable being replaced. They should be removed once that memtable is safely on disk.
+    pub fn rotate(&mut self) -> io::Result<Vec<String>> {
+        let new_index = self.current_index + 1;
+        let new_path = log_path(&self.dir, new_index);
+        let new_file = create_log_file(&new_path)?;
+        let new_len = new_file.metadata()?.len();
+
+        self.current_index = new_index;
+        self.file = new_file;
+        self.len = new_len;
+        let old_path = std::mem::replace(&mut self.current_path, new_path);
+
+        let mut old_paths = std::mem::take(&mut self.replayed_paths);
+        old_paths.push(old_path);
+        Ok(old_paths)
+    }
+}
+
+fn log_path(dir: &str, index: u32) -> String {
+    format!("{}/{:08}.{}", dir, index, WAL_EXTENSION)
+}
+
+// None if the file name is not a number.
+fn log_index(path: &Path) -> Option<u32> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn create_log_file(path: &str) -> io::Result<File> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&WAL_MAGIC)?;
+    Ok(file)
+}
+
+// Returns the biggest sequence number in the log, 0 if it has none.
+fn replay<T: MemTable>(path: &str, memtable: &mut T) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+
+    let mut header = [0u8; WAL_MAGIC.len()];
+    match file.read_exact(&mut header) {
+        Ok(()) if header == WAL_MAGIC => {}
+        Ok(()) => return Err(encoding::corruption_error("Unknown write-ahead log header")),
+        // A crash right after creating the log can cut its header.
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+        Err(e) => return Err(e),
+    }
+
+    let mut reader = BufReader::new(file);
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut last_sequence = 0;
+
+    loop {
+        match read_batch(&mut reader, &mut buffer) {
+            Ok(entries) => {
+                for (key, sequence, value) in entries {
+                    last_sequence = last_sequence.max(sequence);
+                    memtable.set(key, sequence, value);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(last_sequence),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The whole record is read before parsing its entries, so a batch cut by a crash is an
+// UnexpectedEof error and none of its entries are applied.
+fn read_batch<Tr: Read>(reader: &mut Tr, buffer: &mut Vec<u8>) -> io::Result<Vec<Entry>> {
+    let size = encoding::read_next_datum(reader, buffer, FormatVersion::Blocks)?;
+    let mut record = io::Cursor::new(buffer[..size].to_vec());
+
+    let mut entries = Vec::new();
+    while (record.position() as usize) < size {
+        match encoding::read_entry(&mut record, buffer, FormatVersion::Blocks) {
+            Ok(entry) => entries.push(entry),
+            // The record is complete, so a cut entry inside it is corruption, not a crash.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(encoding::corruption_error(
+                    "Incomplete entry in write batch",
+                ))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use super::*;
+    use crate::domain::{Value, Versions};
+    use crate::hashmap_mem_table::HashMapMemTable;
+
+    #[test]
+    fn test_replay_cut_batch() {
+        let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+        fs::create_dir(&tmp_dir).expect("Create tmp folder");
+
+        let mut memtable: HashMapMemTable<Vec<u8>, Versions> = MemTable::new();
+        let (mut wal, _) =
+            WriteAheadLog::open(&tmp_dir, SyncPolicy::Never, &mut memtable).expect("Open log");
+        let mut batch = WriteBatch::new();
+        batch.set(&b"a"[..], &b"pera"[..]);
+        wal.append(1, &batch).expect("Append to log");
+
+        let mut batch = WriteBatch::new();
+        batch.set(&b"b"[..], &b"kiwi"[..]);
+        batch.delete(&b"a"[..]);
+        wal.append(2, &batch).expect("Append to log");
+        drop(wal);
+
+        // A crash in the middle of the second batch loses all of it.
+        let path = log_path(&tmp_dir, 0);
+        let log = fs::read(&path).expect("Read log");
+        fs::write(&path, &log[..log.len() - 2]).expect("Cut log");
+
+        let mut memtable: HashMapMemTable<Vec<u8>, Versions> = MemTable::new();
+        let (_, last_sequence) =
+            WriteAheadLog::open(&tmp_dir, SyncPolicy::Never, &mut memtable).expect("Open log");
+        assert_eq!(last_sequence, 1);
+        assert_eq!(
+            MemTable::get(&memtable, b"a", u64::MAX),
+            Some(&Value::Data(b"pera".to_vec()))
+        );
+        assert_eq!(MemTable::get(&memtable, b"b", u64::MAX), None);
+
+        fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+    }
+
+    #[test]
+    fn test_ignore_files_that_are_not_logs() {
+        let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+        fs::create_dir(&tmp_dir).expect("Create tmp folder");
+
+        let mut memtable: HashMapMemTable<Vec<u8>, Versions> = MemTable::new();
+        let (mut wal, _) =
+            WriteAheadLog::open(&tmp_dir, SyncPolicy::Never, &mut memtable).expect("Open log");
+        let mut batch = WriteBatch::new();
+        batch.set(&b"a"[..], &b"pera"[..]);
+        wal.append(1, &batch).expect("Append to log");
+        drop(wal);
+        fs::write(format!("{}/foo.wal", tmp_dir), b"not a log").expect("Write stray file");
+
+        let mut memtable: HashMapMemTable<Vec<u8>, Versions> = MemTable::new();
+        let (wal, last_sequence) =
+            WriteAheadLog::open(&tmp_dir, SyncPolicy::Never, &mut memtable).expect("Open log");
+        assert_eq!(last_sequence, 1);
+        assert_eq!(
+            MemTable::get(&memtable, b"a", u64::MAX),
+            Some(&Value::Data(b"pera".to_vec()))
+        );
+        assert_eq!(wal.current_index, 1);
+
+        fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::{Duration, SystemTime};
+
+use super::{unix_millis, Value};
+
+// Writes applied together by `KVStore::write`: readers see all of them or none, they are logged
+// as a single record, and they always go to the same memtable. Later writes of a key in the same
+// batch replace earlier ones.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WriteBatch {
+    pub(crate) entries: Vec<(Vec<u8>, Value)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set<Tkey: Into<Vec<u8>>, Tvalue: Into<Vec<u8>>>(&mut self, key: Tkey, value: Tvalue) {
+        self.entries.push((key.into(), Value::Data(value.into())));
+    }
+
+    // The key is read as deleted once `ttl` has passed since this call.
+    pub fn set_with_ttl<Tkey: Into<Vec<u8>>, Tvalue: Into<Vec<u8>>>(
+        &mut self,
+        key: Tkey,
+        value: Tvalue,
+        ttl: Duration,
+    ) {
+        let ttl_millis = ttl.as_millis().min(u64::MAX as u128) as u64;
+        let expires_at = unix_millis(SystemTime::now()).saturating_add(ttl_millis);
+        let value = Value::Expiring {
+            data: value.into(),
+            expires_at,
+        };
+        self.entries.push((key.into(), value));
+    }
+
+    pub fn delete<Tkey: Into<Vec<u8>>>(&mut self, key: Tkey) {
+        self.entries.push((key.into(), Value::Tombstone));
+    }
+
+    // Number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -1,51 +1,77 @@
 use crate::domain;
+use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::hash::Hash;
 
 #[derive(Debug)]
 pub struct HashMapMemTable<Tkey: Ord + Sized + Eq + Hash , Tvalue: Sized> {
     hashmap: HashMap<Tkey, Tvalue>,
+    // Maintained by the MemTable implementation, as it depends on the key and value types.
+    memory_usage: usize,
 }
 
 impl<Tkey: Ord + Sized + Eq + Hash, Tvalue: Sized> HashMapMemTable<Tkey, Tvalue> {
     fn new() -> HashMapMemTable<Tkey, Tvalue> {
-        HashMapMemTable { hashmap: HashMap::new() }
+        HashMapMemTable {
+            hashmap: HashMap::new(),
+            memory_usage: 0,
+        }
     }
 
-    fn set(&mut self, key: Tkey, value: Tvalue) {
-        self.hashmap.insert(key, value);
+    // Returns the value of the key, inserting the default value first if the key is not there.
+    fn get_or_insert_default(&mut self, key: Tkey) -> &mut Tvalue
+    where
+        Tvalue: Default,
+    {
+        self.hashmap.entry(key).or_default()
     }
 
-    fn get(&self, key: &Tkey) -> Option<&Tvalue> {
+    fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<&Tvalue>
+    where
+        Tkey: Borrow<Q>,
+    {
         self.hashmap.get(key)
     }
 
     fn sorted_entries(&self) -> Vec<(&Tkey, &Tvalue)> {
         let mut ret : Vec<(&Tkey, &Tvalue)>= self.hashmap.iter().collect();
-        ret.sort_by(|p1, p2| p1.0.cmp(&p2.0));
+        ret.sort_by(|p1, p2| p1.0.cmp(p2.0));
         ret
     }
 }
 
-impl domain::MemTable for HashMapMemTable<Vec<u8>, Vec<u8>> {
+impl domain::MemTable for HashMapMemTable<Vec<u8>, domain::Versions> {
     fn new() -> Self {
         HashMapMemTable::new()
     }
 
-    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        HashMapMemTable::set(self, key, value)
+    fn set(&mut self, key: Vec<u8>, sequence: u64, value: domain::Value) {
+        let key_size = key.len();
+        self.memory_usage += domain::entry_memory_usage(key_size, &value);
+        let versions = self.get_or_insert_default(key);
+        if let Some(old_value) = domain::add_version(versions, sequence, value) {
+            self.memory_usage -= domain::entry_memory_usage(key_size, &old_value);
+        }
     }
 
-    fn get(&self, key: &Vec<u8>) -> Option<&Vec<u8>> {
+    fn get(&self, key: &[u8], sequence: u64) -> Option<&domain::Value> {
         HashMapMemTable::get(self, key)
+            .and_then(|versions| domain::find_version(versions, sequence))
     }
 
-    fn sorted_entries(&self) -> Vec<(&Vec<u8>, &Vec<u8>)> {
+    fn sorted_entries(&self) -> Vec<(&Vec<u8>, u64, &domain::Value)> {
         HashMapMemTable::sorted_entries(self)
+            .into_iter()
+            .flat_map(|(key, versions)| {
+                versions
+                    .iter()
+                    .map(move |(sequence, value)| (key, *sequence, value))
+            })
+            .collect()
     }
 
-    fn len(&self) -> usize {
-        self.hashmap.len()
+    fn memory_usage(&self) -> usize {
+        self.memory_usage
     }
 }
 
@@ -63,6 +89,11 @@ mod tests {
         domain::memtable_trait_tests::test_insert_same_key(HashMapMemTable::new());
     }
 
+    #[test]
+    fn test_memory_usage() {
+        domain::memtable_trait_tests::test_memory_usage(HashMapMemTable::new());
+    }
+
     #[test]
     fn test_sorted_entries() {
         domain::memtable_trait_tests::test_sorted_entries(HashMapMemTable::new());
@@ -1,34 +1,197 @@
 mod domain;
+// Only used by tests, the store uses the skiplist memtable.
+#[cfg(test)]
 mod vec_mem_table;
+#[cfg(test)]
 mod hashmap_mem_table;
+mod skiplist_mem_table;
 //mod sstable;
 
-type MemTableType = hashmap_mem_table::HashMapMemTable<Vec<u8>, Vec<u8>>;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use domain::error::{Error, Result};
+pub use domain::options::{Compression, Options, OptionsBuilder, SyncPolicy};
+pub use domain::stats::Stats;
+pub use domain::write_batch::WriteBatch;
+pub use domain::Swap;
+
+type MemTableType = skiplist_mem_table::SkipListMemTable;
 type DomainKVStoreType = domain::KVStore<MemTableType>;
 
+// Handle to an open store. It can be cloned cheaply and sent to other threads, and all the
+// clones use the same store. Reads run in parallel with each other and with writes.
+#[derive(Clone)]
 pub struct KVStore {
-    kv_store_domain: DomainKVStoreType,
+    kv_store_domain: Arc<DomainKVStoreType>,
 }
 
 impl<'a> KVStore {
-    pub fn new(dir: &str) -> KVStore {
-        let kv_store_domain: DomainKVStoreType = domain::KVStore::new(dir);
-        KVStore { kv_store_domain }
+    // Opens the store in `dir`, creating it if it doesn't exist. `Options::load(dir)` gives the
+    // options saved in the directory.
+    pub fn open(dir: &str, options: Options) -> Result<KVStore> {
+        let kv_store_domain: DomainKVStoreType = domain::KVStore::open(dir, options)?;
+        Ok(KVStore {
+            kv_store_domain: Arc::new(kv_store_domain),
+        })
     }
 
-    pub fn set<Tkey: Into<Vec<u8>>, Tvalue: Into<Vec<u8>>>(&mut self, key: Tkey, value: Tvalue) {
+    // Fails with InvalidArgument if the key or the value are bigger than the configured maximums.
+    pub fn set<Tkey: Into<Vec<u8>>, Tvalue: Into<Vec<u8>>>(
+        &self,
+        key: Tkey,
+        value: Tvalue,
+    ) -> Result<()> {
         self.kv_store_domain.set(key.into(), value.into())
     }
 
-    pub fn get<Tkey: Into<&'a Vec<u8>>>(&self, key: Tkey) -> Option<Vec<u8>> {
-        self.kv_store_domain.get(key.into())
+    // Like `set`, but the key is read as deleted once `ttl` has passed. Expired entries are
+    // removed from disk by compactions.
+    pub fn set_with_ttl<Tkey: Into<Vec<u8>>, Tvalue: Into<Vec<u8>>>(
+        &self,
+        key: Tkey,
+        value: Tvalue,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.kv_store_domain
+            .set_with_ttl(key.into(), value.into(), ttl)
     }
 
-    pub fn delete<Tkey: Into<&'a Vec<u8>>>(&mut self, key: Tkey) {
+    // Fails with Corruption if the data read from disk is not valid, instead of returning a
+    // wrong value.
+    pub fn get<Tkey: Into<&'a Vec<u8>>>(&self, key: Tkey) -> Result<Option<Vec<u8>>> {
+        self.kv_store_domain.get(key.into(), None)
+    }
+
+    // Iterates the entries with keys in the range, in key order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        self.kv_store_domain.range(
+            (range.start_bound().cloned(), range.end_bound().cloned()),
+            None,
+        )
+    }
+
+    // Iterates the entries with keys starting with `prefix`, in key order.
+    pub fn scan_prefix<Tkey: Into<Vec<u8>>>(
+        &self,
+        prefix: Tkey,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        self.kv_store_domain.scan_prefix(&prefix.into(), None)
+    }
+
+    // Takes a snapshot of the store as it is now. Reads through the snapshot don't see later
+    // writes, so several keys can be read as of the same point in time.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            snapshot: self.kv_store_domain.snapshot()?,
+        })
+    }
+
+    pub fn delete<Tkey: Into<&'a Vec<u8>>>(&self, key: Tkey) -> Result<()> {
         self.kv_store_domain.delete(key.into())
     }
 
-    pub fn save_memtable(&mut self) {
+    // Applies all the writes of the batch atomically: reads and recovery after a crash see all of
+    // them or none. Fails with InvalidArgument, writing nothing, if any key or value is bigger
+    // than the configured maximums.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.kv_store_domain.write(batch)
+    }
+
+    // Sets the key to `new`, or deletes it if it is None, only if its current value is
+    // `expected`, where None means that the key doesn't exist. No other write can happen in
+    // between. Returns Swap::Failed with the current value if it didn't match.
+    pub fn compare_and_swap<Tkey: Into<Vec<u8>>>(
+        &self,
+        key: Tkey,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<Swap> {
+        self.kv_store_domain
+            .compare_and_swap(&key.into(), expected, new)
+    }
+
+    // Sets the key only if it doesn't exist. Returns Swap::Failed with the current value if it
+    // does.
+    pub fn set_if_absent<Tkey: Into<Vec<u8>>, Tvalue: Into<Vec<u8>>>(
+        &self,
+        key: Tkey,
+        value: Tvalue,
+    ) -> Result<Swap> {
+        self.kv_store_domain
+            .set_if_absent(&key.into(), value.into())
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.kv_store_domain.stats()
+    }
+
+    // Stops starting new compactions, for example while the machine is busy, until
+    // `resume_compactions` is called. Compactions that are running finish. Meanwhile the
+    // sstables saved from memtables pile up, so reads get slower.
+    pub fn pause_compactions(&self) {
+        self.kv_store_domain.pause_compactions()
+    }
+
+    pub fn resume_compactions(&self) {
+        self.kv_store_domain.resume_compactions()
+    }
+
+    // Starts saving the memtable to disk in the background. Errors of the previous save are
+    // returned here and by the following writes.
+    pub fn save_memtable(&self) -> Result<()> {
         self.kv_store_domain.save_memtable()
     }
+
+    // Saves the memtable and waits for it and the compactions it needs, returning any error.
+    // Every clone of the handle is closed. Dropping the last clone does the same but can only
+    // print the errors.
+    pub fn close(&self) -> Result<()> {
+        self.kv_store_domain.close()
+    }
+}
+
+// Consistent view of a store, taken with `KVStore::snapshot`. Its reads take the store they were
+// taken from, and fail with InvalidArgument for any other one. The data it can read is kept on
+// disk until it is dropped, so it shouldn't be kept longer than needed.
+#[derive(Debug)]
+pub struct Snapshot {
+    snapshot: domain::snapshot::Snapshot,
+}
+
+impl<'a> Snapshot {
+    pub fn get<Tkey: Into<&'a Vec<u8>>>(
+        &self,
+        store: &KVStore,
+        key: Tkey,
+    ) -> Result<Option<Vec<u8>>> {
+        store.kv_store_domain.get(key.into(), Some(&self.snapshot))
+    }
+
+    // Iterates the entries with keys in the range, in key order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        store: &KVStore,
+        range: R,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        store.kv_store_domain.range(
+            (range.start_bound().cloned(), range.end_bound().cloned()),
+            Some(&self.snapshot),
+        )
+    }
+
+    // Iterates the entries with keys starting with `prefix`, in key order.
+    pub fn scan_prefix<Tkey: Into<Vec<u8>>>(
+        &self,
+        store: &KVStore,
+        prefix: Tkey,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+        store
+            .kv_store_domain
+            .scan_prefix(&prefix.into(), Some(&self.snapshot))
+    }
 }
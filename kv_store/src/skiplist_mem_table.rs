@@ -0,0 +1,337 @@
+use crate::domain::{self, Value};
+use std::cmp::Ordering as KeyOrdering;
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Skiplist memtable, like the one of LevelDB. Entries are kept sorted as they are inserted, so
+// saving the memtable doesn't need to sort it. Each version of a key is a node, and versions of
+// the same key go from newest to oldest.
+//
+// Readers don't take any lock, and can run while an entry is being inserted. Writers are
+// serialized by a mutex. This works because nodes are never removed until the memtable is
+// dropped, and a node is linked into the list only after it is fully built:
+//  - Links are published with Release stores and read with Acquire loads, so a reader that sees
+//    a node also sees its key, value and next links.
+//  - Setting a version that is already there replaces the value pointer of its node. The old
+//    value may still be borrowed by a reader, so it is kept until nobody can hold a reference to
+//    it: the next call that takes the memtable by &mut, or the drop.
+
+const MAX_HEIGHT: usize = 12;
+// Each level has about 1/BRANCHING of the nodes of the level below.
+const BRANCHING: u64 = 4;
+
+struct Node {
+    key: Vec<u8>,
+    sequence: u64,
+    // Null only in the head node.
+    value: AtomicPtr<Value>,
+    next: Box<[AtomicPtr<Node>]>,
+}
+
+impl Node {
+    fn new(key: Vec<u8>, sequence: u64, value: *mut Value, height: usize) -> Node {
+        Node {
+            key,
+            sequence,
+            value: AtomicPtr::new(value),
+            next: (0..height)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+        }
+    }
+
+    fn value(&self) -> &Value {
+        // Safety: values of linked nodes are never null, and they are only freed when no
+        // reference to the memtable can exist.
+        unsafe { &*self.value.load(Ordering::Acquire) }
+    }
+
+    // True if the node goes before the version of `key` with `sequence`.
+    fn is_before(&self, key: &[u8], sequence: u64) -> bool {
+        match self.key.as_slice().cmp(key) {
+            KeyOrdering::Less => true,
+            KeyOrdering::Equal => self.sequence > sequence,
+            KeyOrdering::Greater => false,
+        }
+    }
+}
+
+struct Writer {
+    // State of the xorshift generator used for node heights.
+    random: u64,
+    // Values replaced while readers could be using them. They are boxed so they don't move.
+    #[allow(clippy::vec_box)]
+    replaced_values: Vec<Box<Value>>,
+}
+
+pub struct SkipListMemTable {
+    head: Box<Node>,
+    // Number of levels in use. It only grows.
+    height: AtomicUsize,
+    memory_usage: AtomicUsize,
+    writer: Mutex<Writer>,
+}
+
+impl SkipListMemTable {
+    pub fn new() -> SkipListMemTable {
+        SkipListMemTable {
+            head: Box::new(Node::new(Vec::new(), 0, ptr::null_mut(), MAX_HEIGHT)),
+            height: AtomicUsize::new(1),
+            memory_usage: AtomicUsize::new(0),
+            writer: Mutex::new(Writer {
+                random: 0xdead_beef,
+                replaced_values: Vec::new(),
+            }),
+        }
+    }
+
+    // Adds a version of the key. It can be called while other threads read the memtable, but
+    // replaced values are only freed by `set` or when the memtable is dropped.
+    pub fn insert(&self, key: Vec<u8>, sequence: u64, value: Value) {
+        let mut writer = self.writer.lock().unwrap();
+
+        let mut prev: [*const Node; MAX_HEIGHT] = [&*self.head; MAX_HEIGHT];
+        let next = self.find_greater_or_equal(&key, sequence, Some(&mut prev));
+        let usage = domain::entry_memory_usage(key.len(), &value);
+
+        // Safety: linked nodes are valid until the memtable is dropped.
+        if let Some(node) = unsafe { next.as_ref() } {
+            if node.key == key && node.sequence == sequence {
+                self.memory_usage.fetch_add(usage, Ordering::Relaxed);
+                let old_value = node
+                    .value
+                    .swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+                // Safety: the pointer came from Box::into_raw and has just been unlinked.
+                let old_value = unsafe { Box::from_raw(old_value) };
+                self.memory_usage.fetch_sub(
+                    domain::entry_memory_usage(key.len(), &old_value),
+                    Ordering::Relaxed,
+                );
+                writer.replaced_values.push(old_value);
+                return;
+            }
+        }
+
+        let height = random_height(&mut writer.random);
+        // Levels above the current height start at the head. Readers that see the new height
+        // before the node is linked just find empty levels.
+        let current_height = self.height.load(Ordering::Relaxed);
+        if height > current_height {
+            self.height.store(height, Ordering::Relaxed);
+        }
+
+        let node = Node::new(key, sequence, Box::into_raw(Box::new(value)), height);
+        for (level, next) in node.next.iter().enumerate() {
+            // Safety: `prev` only has the head and linked nodes.
+            let prev_next = unsafe { &(*prev[level]).next[level] };
+            next.store(prev_next.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        let node = Box::into_raw(Box::new(node));
+        for (level, prev) in prev.iter().enumerate().take(height) {
+            // Safety: as above. The node is complete before it is published.
+            unsafe { (**prev).next[level].store(node, Ordering::Release) };
+        }
+
+        self.memory_usage.fetch_add(usage, Ordering::Relaxed);
+    }
+
+    // First node that doesn't go before the version of `key` with `sequence`, or null. If `prev`
+    // is given, it is filled with the last node before that position in each level.
+    fn find_greater_or_equal(
+        &self,
+        key: &[u8],
+        sequence: u64,
+        mut prev: Option<&mut [*const Node; MAX_HEIGHT]>,
+    ) -> *const Node {
+        let mut node: &Node = &self.head;
+        let mut level = self.height.load(Ordering::Relaxed) - 1;
+        loop {
+            let next = node.next[level].load(Ordering::Acquire);
+            // Safety: linked nodes are valid until the memtable is dropped.
+            match unsafe { next.as_ref() } {
+                Some(next_node) if next_node.is_before(key, sequence) => node = next_node,
+                _ => {
+                    if let Some(prev) = prev.as_deref_mut() {
+                        prev[level] = node;
+                    }
+                    if level == 0 {
+                        return next;
+                    }
+                    level -= 1;
+                }
+            }
+        }
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        let first = self.head.next[0].load(Ordering::Acquire);
+        // Safety: linked nodes are valid until the memtable is dropped.
+        std::iter::successors(unsafe { first.as_ref() }, |node| unsafe {
+            node.next[0].load(Ordering::Acquire).as_ref()
+        })
+    }
+}
+
+fn random_height(random: &mut u64) -> usize {
+    let mut height = 1;
+    while height < MAX_HEIGHT && next_random(random).is_multiple_of(BRANCHING) {
+        height += 1;
+    }
+    height
+}
+
+fn next_random(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+impl domain::MemTable for SkipListMemTable {
+    fn new() -> Self {
+        SkipListMemTable::new()
+    }
+
+    fn set(&mut self, key: Vec<u8>, sequence: u64, value: Value) {
+        self.insert(key, sequence, value);
+        // Nobody can be reading, so replaced values can be freed.
+        self.writer.get_mut().unwrap().replaced_values.clear();
+    }
+
+    fn get(&self, key: &[u8], sequence: u64) -> Option<&Value> {
+        // The first node after the position of the version is the newest version of the key
+        // written at or before `sequence`, if it has the same key.
+        // Safety: linked nodes are valid until the memtable is dropped.
+        let node = unsafe { self.find_greater_or_equal(key, sequence, None).as_ref() }?;
+        if node.key == key {
+            Some(node.value())
+        } else {
+            None
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    fn sorted_entries(&self) -> Vec<(&Vec<u8>, u64, &Value)> {
+        self.nodes()
+            .map(|node| (&node.key, node.sequence, node.value()))
+            .collect()
+    }
+}
+
+impl domain::ConcurrentMemTable for SkipListMemTable {
+    fn insert(&self, key: Vec<u8>, sequence: u64, value: Value) {
+        SkipListMemTable::insert(self, key, sequence, value);
+    }
+}
+
+impl Drop for SkipListMemTable {
+    fn drop(&mut self) {
+        let mut next = self.head.next[0].load(Ordering::Relaxed);
+        while !next.is_null() {
+            // Safety: every linked node and its value were created with Box::into_raw, and are
+            // freed once.
+            let node = unsafe { Box::from_raw(next) };
+            unsafe { drop(Box::from_raw(node.value.load(Ordering::Relaxed))) };
+            next = node.next[0].load(Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Debug for SkipListMemTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SkipListMemTable")
+            .field("height", &self.height.load(Ordering::Relaxed))
+            .field("memory_usage", &self.memory_usage.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::MemTable;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_basic() {
+        domain::memtable_trait_tests::test_basic(SkipListMemTable::new());
+    }
+
+    #[test]
+    fn test_insert_same_key() {
+        domain::memtable_trait_tests::test_insert_same_key(SkipListMemTable::new());
+    }
+
+    #[test]
+    fn test_memory_usage() {
+        domain::memtable_trait_tests::test_memory_usage(SkipListMemTable::new());
+    }
+
+    #[test]
+    fn test_sorted_entries() {
+        domain::memtable_trait_tests::test_sorted_entries(SkipListMemTable::new());
+    }
+
+    #[test]
+    fn test_read_while_inserting() {
+        let memtable = Arc::new(SkipListMemTable::new());
+
+        let writer = {
+            let memtable = memtable.clone();
+            thread::spawn(move || {
+                for i in 0..10_000u32 {
+                    // Reversed bytes, so keys are not inserted in order.
+                    let key = i.to_le_bytes().to_vec();
+                    let sequence = 2 * i as u64 + 1;
+                    memtable.insert(key.clone(), sequence, Value::Tombstone);
+                    memtable.insert(key, sequence + 1, Value::Data(i.to_be_bytes().to_vec()));
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let memtable = memtable.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        let entries = memtable.sorted_entries();
+                        assert!(entries.windows(2).all(|pair| {
+                            pair[0].0 < pair[1].0
+                                || (pair[0].0 == pair[1].0 && pair[0].1 > pair[1].1)
+                        }));
+                        for i in (0..10_000u32).step_by(100) {
+                            match memtable.get(&i.to_le_bytes(), u64::MAX) {
+                                None | Some(Value::Tombstone) => {}
+                                Some(value) => {
+                                    assert_eq!(value, &Value::Data(i.to_be_bytes().to_vec()))
+                                }
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let entries = memtable.sorted_entries();
+        assert_eq!(entries.len(), 20_000);
+        assert_eq!(
+            memtable.get(&1234u32.to_le_bytes(), u64::MAX),
+            Some(&Value::Data(1234u32.to_be_bytes().to_vec()))
+        );
+        assert_eq!(
+            memtable.get(&1234u32.to_le_bytes(), 2 * 1234 + 1),
+            Some(&Value::Tombstone)
+        );
+    }
+}
@@ -1,22 +1,41 @@
 use crate::domain;
+use std::borrow::Borrow;
 
 #[derive(Debug)]
 pub struct VecMemTable<Tkey: Ord + Sized, Tvalue: Sized> {
     vec: Vec<(Tkey, Tvalue)>,
+    // Maintained by the MemTable implementation, as it depends on the key and value types.
+    memory_usage: usize,
 }
 
 impl<Tkey: Ord + Sized, Tvalue: Sized> VecMemTable<Tkey, Tvalue> {
     fn new() -> VecMemTable<Tkey, Tvalue> {
-        VecMemTable { vec: vec![] }
+        VecMemTable {
+            vec: vec![],
+            memory_usage: 0,
+        }
     }
 
-    fn set(&mut self, key: Tkey, value: Tvalue) {
-        self.vec.retain(|p| p.0 != key);
-        self.vec.push((key, value));
+    // Returns the value of the key, inserting the default value first if the key is not there.
+    fn get_or_insert_default(&mut self, key: Tkey) -> &mut Tvalue
+    where
+        Tvalue: Default,
+    {
+        let i = match self.vec.iter().position(|p| p.0 == key) {
+            Some(i) => i,
+            None => {
+                self.vec.push((key, Tvalue::default()));
+                self.vec.len() - 1
+            }
+        };
+        &mut self.vec[i].1
     }
 
-    fn get(&self, key: &Tkey) -> Option<&Tvalue> {
-        let pair = self.vec.iter().find(|&x| x.0 == *key);
+    fn get<Q: ?Sized + Eq>(&self, key: &Q) -> Option<&Tvalue>
+    where
+        Tkey: Borrow<Q>,
+    {
+        let pair = self.vec.iter().find(|&x| x.0.borrow() == key);
         match pair {
             Some(p) => Some(&p.1),
             None => None,
@@ -29,30 +48,42 @@ impl<Tkey: Ord + Sized, Tvalue: Sized> VecMemTable<Tkey, Tvalue> {
             let p = &self.vec[i];
             ret.push((&p.0, &p.1));
         }
-        ret.sort_by(|p1, p2| p1.0.cmp(&p2.0));
+        ret.sort_by(|p1, p2| p1.0.cmp(p2.0));
         ret
     }
 }
 
-impl domain::MemTable for VecMemTable<Vec<u8>, Vec<u8>> {
+impl domain::MemTable for VecMemTable<Vec<u8>, domain::Versions> {
     fn new() -> Self {
         VecMemTable::new()
     }
 
-    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
-        VecMemTable::set(self, key, value)
+    fn set(&mut self, key: Vec<u8>, sequence: u64, value: domain::Value) {
+        let key_size = key.len();
+        self.memory_usage += domain::entry_memory_usage(key_size, &value);
+        let versions = self.get_or_insert_default(key);
+        if let Some(old_value) = domain::add_version(versions, sequence, value) {
+            self.memory_usage -= domain::entry_memory_usage(key_size, &old_value);
+        }
     }
 
-    fn get(&self, key: &Vec<u8>) -> Option<&Vec<u8>> {
-        VecMemTable::get(self, key)
+    fn get(&self, key: &[u8], sequence: u64) -> Option<&domain::Value> {
+        VecMemTable::get(self, key).and_then(|versions| domain::find_version(versions, sequence))
     }
 
-    fn sorted_entries(&self) -> Vec<(&Vec<u8>, &Vec<u8>)> {
+    fn sorted_entries(&self) -> Vec<(&Vec<u8>, u64, &domain::Value)> {
         VecMemTable::sorted_entries(self)
+            .into_iter()
+            .flat_map(|(key, versions)| {
+                versions
+                    .iter()
+                    .map(move |(sequence, value)| (key, *sequence, value))
+            })
+            .collect()
     }
 
-    fn len(&self) -> usize {
-        self.vec.len()
+    fn memory_usage(&self) -> usize {
+        self.memory_usage
     }
 }
 
@@ -70,6 +101,11 @@ mod tests {
         domain::memtable_trait_tests::test_insert_same_key(VecMemTable::new());
     }
 
+    #[test]
+    fn test_memory_usage() {
+        domain::memtable_trait_tests::test_memory_usage(VecMemTable::new());
+    }
+
     #[test]
     fn test_sorted_entries() {
         domain::memtable_trait_tests::test_sorted_entries(VecMemTable::new());
@@ -3,6 +3,7 @@ extern crate rand;
 use std::thread;
 use std::time::Duration;
 use std::fs;
+use std::iter;
 
 macro_rules! byte_vec {
     ($a: expr) => {
@@ -18,23 +19,23 @@ fn random_bytes() -> Vec<u8> {
 fn create_kvstore_in_tmp_folder() -> (kv_store::KVStore, String) {
     let test_dir = format!("./tmp-{}/", rand::random::<u64>());
 
-    let kv_store = kv_store::KVStore::new(&test_dir);
+    let kv_store = kv_store::KVStore::open(&test_dir, kv_store::Options::default()).unwrap();
 
     (kv_store, test_dir)
 }
 
 #[test]
 fn test_basic() {
-    let (mut kv, tmp_dir) = create_kvstore_in_tmp_folder();
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
 
-    kv.set("a", "mandarina");
-    kv.set("b", "platan");
-    kv.set("c", "poma");
-    kv.delete(&byte_vec!("c"));
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
+    kv.set("c", "poma").unwrap();
+    kv.delete(&byte_vec!("c")).unwrap();
 
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("mandarina")));
-    assert_eq!(kv.get(&byte_vec!("b")), Some(byte_vec!("platan")));
-    assert_eq!(kv.get(&byte_vec!("c")), None);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("mandarina")));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(byte_vec!("platan")));
+    assert_eq!(kv.get(&byte_vec!("c")).unwrap(), None);
 
     std::mem::drop(kv);
     fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
@@ -42,26 +43,26 @@ fn test_basic() {
 
 #[test]
 fn test_basic_while_saving_memtable() {
-    let (mut kv, tmp_dir) = create_kvstore_in_tmp_folder();
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
 
-    kv.set("a", "mandarina");
-    kv.set("b", "platan");
-    kv.set("c", "poma");
-    kv.delete(&byte_vec!("c"));
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
+    kv.set("c", "poma").unwrap();
+    kv.delete(&byte_vec!("c")).unwrap();
 
-    kv.save_memtable();
+    kv.save_memtable().unwrap();
 
     // Test while saving memtable
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("mandarina")));
-    assert_eq!(kv.get(&byte_vec!("b")), Some(byte_vec!("platan")));
-    assert_eq!(kv.get(&byte_vec!("c")), None);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("mandarina")));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(byte_vec!("platan")));
+    assert_eq!(kv.get(&byte_vec!("c")).unwrap(), None);
 
     thread::sleep(Duration::from_secs(1));
 
     // Test after memtable is on disk
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("mandarina")));
-    assert_eq!(kv.get(&byte_vec!("b")), Some(byte_vec!("platan")));
-    assert_eq!(kv.get(&byte_vec!("c")), None);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("mandarina")));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(byte_vec!("platan")));
+    assert_eq!(kv.get(&byte_vec!("c")).unwrap(), None);
 
     std::mem::drop(kv);
     fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
@@ -69,27 +70,27 @@ fn test_basic_while_saving_memtable() {
 
 #[test]
 fn test_delete_after_saving_memtable() {
-    let (mut kv, tmp_dir) = create_kvstore_in_tmp_folder();
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
 
-    kv.set("a", "mandarina");
-    kv.set("b", "platan");
-    kv.set("c", "poma");
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
+    kv.set("c", "poma").unwrap();
 
-    kv.save_memtable();
+    kv.save_memtable().unwrap();
 
-    kv.delete(&byte_vec!("c"));
+    kv.delete(&byte_vec!("c")).unwrap();
 
     // Test while saving memtable
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("mandarina")));
-    assert_eq!(kv.get(&byte_vec!("b")), Some(byte_vec!("platan")));
-    assert_eq!(kv.get(&byte_vec!("c")), None);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("mandarina")));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(byte_vec!("platan")));
+    assert_eq!(kv.get(&byte_vec!("c")).unwrap(), None);
 
     thread::sleep(Duration::from_secs(1));
 
     // Test after memtable is on disk
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("mandarina")));
-    assert_eq!(kv.get(&byte_vec!("b")), Some(byte_vec!("platan")));
-    assert_eq!(kv.get(&byte_vec!("c")), None);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("mandarina")));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(byte_vec!("platan")));
+    assert_eq!(kv.get(&byte_vec!("c")).unwrap(), None);
 
     std::mem::drop(kv);
     fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
@@ -97,22 +98,22 @@ fn test_delete_after_saving_memtable() {
 
 #[test]
 fn test_insert_same_key() {
-    let (mut kv, tmp_dir) = create_kvstore_in_tmp_folder();
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
 
-    kv.set("a", "mandarina");
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("mandarina")));
+    kv.set("a", "mandarina").unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("mandarina")));
 
-    kv.set("a", "platan");
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("platan")));
+    kv.set("a", "platan").unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("platan")));
 
     for _ in 0..10_000 {
-        kv.set(random_bytes(), random_bytes());
+        kv.set(random_bytes(), random_bytes()).unwrap();
     }
 
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("platan")));
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("platan")));
 
-    kv.set("a", "ana");
-    assert_eq!(kv.get(&byte_vec!("a")), Some(byte_vec!("ana")));
+    kv.set("a", "ana").unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("ana")));
 
     std::mem::drop(kv);
     fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
@@ -120,22 +121,621 @@ fn test_insert_same_key() {
 
 #[test]
 fn test_persistance() {
-    let (mut kv, tmp_dir) = create_kvstore_in_tmp_folder();
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
 
-    kv.set("a", "mandarina");
+    kv.set("a", "mandarina").unwrap();
     for _ in 0..10_000 {
-        kv.set(random_bytes(), random_bytes());
+        kv.set(random_bytes(), random_bytes()).unwrap();
     }
-    kv.set("b", "gerard");
-    kv.set("a", "platan");
+    kv.set("b", "gerard").unwrap();
+    kv.set("a", "platan").unwrap();
     // Drop just after set, to test that memtable is stored to lsm_tree and lsm_tree waits for
     // save thread to finish.
     std::mem::drop(kv);
 
-    let new_kv = kv_store::KVStore::new(&tmp_dir);
-    assert_eq!(new_kv.get(&byte_vec!("a")), Some(byte_vec!("platan")));
-    assert_eq!(new_kv.get(&byte_vec!("b")), Some(byte_vec!("gerard")));
+    let new_kv = kv_store::KVStore::open(&tmp_dir, kv_store::Options::default()).unwrap();
+    assert_eq!(new_kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("platan")));
+    assert_eq!(new_kv.get(&byte_vec!("b")).unwrap(), Some(byte_vec!("gerard")));
     std::mem::drop(new_kv);
 
     fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
 }
+
+#[test]
+fn test_recover_from_write_ahead_log() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
+    kv.save_memtable().unwrap();
+    // Let the memtable reach the disk before the crash.
+    thread::sleep(Duration::from_secs(1));
+
+    kv.set("c", "poma").unwrap();
+    kv.set("a", "ana").unwrap();
+    kv.delete(&byte_vec!("b")).unwrap();
+    // Simulate a crash: the memtable is never saved to disk.
+    std::mem::forget(kv);
+
+    let new_kv = kv_store::KVStore::open(&tmp_dir, kv_store::Options::default()).unwrap();
+    assert_eq!(new_kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("ana")));
+    assert_eq!(new_kv.get(&byte_vec!("b")).unwrap(), None);
+    assert_eq!(new_kv.get(&byte_vec!("c")).unwrap(), Some(byte_vec!("poma")));
+    std::mem::drop(new_kv);
+
+    // Once the recovered memtable is saved the logs are not needed anymore.
+    let new_kv = kv_store::KVStore::open(&tmp_dir, kv_store::Options::default()).unwrap();
+    assert_eq!(new_kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("ana")));
+    assert_eq!(new_kv.get(&byte_vec!("b")).unwrap(), None);
+    assert_eq!(new_kv.get(&byte_vec!("c")).unwrap(), Some(byte_vec!("poma")));
+    std::mem::drop(new_kv);
+
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_range_and_scan_prefix() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+
+    kv.set("fruita:poma", "vermella").unwrap();
+    kv.set("fruita:platan", "groc").unwrap();
+    kv.set("ciutat:bcn", "Barcelona").unwrap();
+    kv.set("fruita:kiwi", "verd").unwrap();
+    kv.save_memtable().unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    kv.set("fruita:poma", "verda").unwrap();
+    kv.set("fruita:pera", "verda").unwrap();
+    kv.save_memtable().unwrap();
+
+    // The last memtable might still be saving while we read.
+    kv.delete(&byte_vec!("fruita:kiwi")).unwrap();
+    kv.set("fruita:mandarina", "taronja").unwrap();
+    kv.set("verdura:pastanaga", "taronja").unwrap();
+
+    let expected_fruits = vec![
+        (byte_vec!("fruita:mandarina"), byte_vec!("taronja")),
+        (byte_vec!("fruita:pera"), byte_vec!("verda")),
+        (byte_vec!("fruita:platan"), byte_vec!("groc")),
+        (byte_vec!("fruita:poma"), byte_vec!("verda")),
+    ];
+    assert_eq!(
+        kv.scan_prefix("fruita:").map(Result::unwrap).collect::<Vec<_>>(),
+        expected_fruits
+    );
+
+    assert_eq!(
+        kv.range(byte_vec!("fruita:pera")..byte_vec!("fruita:poma"))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>(),
+        expected_fruits[1..3].to_vec()
+    );
+
+    let all_keys: Vec<Vec<u8>> = kv.range(..).map(|entry| entry.unwrap().0).collect();
+    assert_eq!(
+        all_keys,
+        vec![
+            byte_vec!("ciutat:bcn"),
+            byte_vec!("fruita:mandarina"),
+            byte_vec!("fruita:pera"),
+            byte_vec!("fruita:platan"),
+            byte_vec!("fruita:poma"),
+            byte_vec!("verdura:pastanaga"),
+        ]
+    );
+
+    assert_eq!(kv.scan_prefix("carn:").count(), 0);
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_store_any_value() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+
+    // Deletions used to be stored as this value, so it could not be stored.
+    let old_tombstone: Vec<u8> = vec![
+        179, 210, 155, 16, 110, 229, 104, 202, 72, 124, 209, 13, 85, 192, 56, 71, 239, 10, 116,
+        199, 186, 205, 163, 143, 3, 43, 125, 16, 157, 22, 47, 244,
+    ];
+    kv.set("a", old_tombstone.clone()).unwrap();
+    kv.set("b", vec![]).unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(old_tombstone.clone()));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(vec![]));
+
+    kv.save_memtable().unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(old_tombstone));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(vec![]));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_big_keys_and_values() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+
+    let big_key = vec![1u8; 50_000];
+    let big_value: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+    kv.set(big_key.clone(), big_value.clone()).unwrap();
+    kv.set("a", big_value.clone()).unwrap();
+    kv.save_memtable().unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    assert_eq!(kv.get(&big_key).unwrap(), Some(big_value.clone()));
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(big_value.clone()));
+
+    std::mem::drop(kv);
+    let options = kv_store::Options::builder()
+        .max_value_size(1000)
+        .max_key_size(10)
+        .build()
+        .unwrap();
+    let kv = kv_store::KVStore::open(&tmp_dir, options).unwrap();
+
+    let error = kv.set("b", big_value).unwrap_err();
+    assert!(matches!(error, kv_store::Error::InvalidArgument(_)), "{:?}", error);
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), None);
+
+    let error = kv.delete(&big_key).unwrap_err();
+    assert!(matches!(error, kv_store::Error::InvalidArgument(_)), "{:?}", error);
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_get_reports_corrupted_tables() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+
+    for i in 0..1_000u32 {
+        kv.set(i.to_be_bytes().to_vec(), format!("value {}", i)).unwrap();
+    }
+    kv.save_memtable().unwrap();
+    std::mem::drop(kv);
+
+    // Flip a byte in the middle of the data blocks of the table.
+    let sstable_path = fs::read_dir(&tmp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "sstable"))
+        .expect("Should have an sstable");
+    let mut bytes = fs::read(&sstable_path).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0x01;
+    fs::write(&sstable_path, bytes).unwrap();
+
+    let kv = kv_store::KVStore::open(&tmp_dir, kv_store::Options::default()).unwrap();
+    let mut corrupted_keys = 0;
+    for i in 0..1_000u32 {
+        match kv.get(&i.to_be_bytes().to_vec()) {
+            Ok(value) => assert_eq!(value, Some(format!("value {}", i).into_bytes())),
+            Err(kv_store::Error::Corruption(_)) => corrupted_keys += 1,
+            Err(error) => panic!("Unexpected error: {:?}", error),
+        }
+    }
+    assert!(corrupted_keys > 0);
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_close() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+
+    kv.set("a", "mandarina").unwrap();
+    kv.close().unwrap();
+
+    assert!(matches!(kv.get(&byte_vec!("a")), Err(kv_store::Error::Closed)));
+    assert!(matches!(kv.set("b", "platan"), Err(kv_store::Error::Closed)));
+    assert!(matches!(kv.close(), Err(kv_store::Error::Closed)));
+    std::mem::drop(kv);
+
+    let kv = kv_store::KVStore::open(&tmp_dir, kv_store::Options::default()).unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("mandarina")));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_options_file() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    fs::create_dir(&tmp_dir).expect("Create tmp folder");
+    // Memtables of a few entries.
+    fs::write(
+        format!("{}/OPTIONS", tmp_dir),
+        "memtable_size = 600\nl0_compaction_trigger = 2\n",
+    )
+    .unwrap();
+
+    let options = kv_store::Options::load(&tmp_dir).unwrap();
+    let kv = kv_store::KVStore::open(&tmp_dir, options).unwrap();
+    for i in 0..50u32 {
+        kv.set(i.to_be_bytes().to_vec(), format!("value {}", i)).unwrap();
+    }
+
+    // The memtable has been saved several times without calling save_memtable.
+    let sstables = fs::read_dir(&tmp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sstable"))
+        .count();
+    assert!(sstables > 0);
+    for i in 0..50u32 {
+        assert_eq!(
+            kv.get(&i.to_be_bytes().to_vec()).unwrap(),
+            Some(format!("value {}", i).into_bytes())
+        );
+    }
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_memtable_size_counts_bytes() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    // With a single memtable waiting to be saved, saving the next one waits until it is on disk.
+    let options = kv_store::Options::builder()
+        .memtable_size(1_000_000)
+        .max_immutable_memtables(1)
+        .build()
+        .unwrap();
+    let kv = kv_store::KVStore::open(&tmp_dir, options).unwrap();
+
+    // Few entries, but big enough to fill several memtables.
+    let big_value = vec![7u8; 300_000];
+    for i in 0..10u32 {
+        kv.set(i.to_be_bytes().to_vec(), big_value.clone()).unwrap();
+    }
+
+    let sstables = fs::read_dir(&tmp_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sstable"))
+        .count();
+    assert!(sstables > 0);
+    assert_eq!(kv.get(&0u32.to_be_bytes().to_vec()).unwrap(), Some(big_value));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_snapshot() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    // Every saved memtable is compacted into level 1, so old versions go through compactions.
+    let options = kv_store::Options::builder()
+        .l0_compaction_trigger(1)
+        .build()
+        .unwrap();
+    let kv = kv_store::KVStore::open(&tmp_dir, options).unwrap();
+
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
+    let snapshot = kv.snapshot().unwrap();
+    kv.set("a", "poma").unwrap();
+    kv.delete(&byte_vec!("b")).unwrap();
+    kv.set("c", "pera").unwrap();
+
+    let check_snapshot = |kv: &kv_store::KVStore| {
+        assert_eq!(
+            snapshot.get(kv, &byte_vec!("a")).unwrap(),
+            Some(byte_vec!("mandarina"))
+        );
+        assert_eq!(
+            snapshot.get(kv, &byte_vec!("b")).unwrap(),
+            Some(byte_vec!("platan"))
+        );
+        assert_eq!(snapshot.get(kv, &byte_vec!("c")).unwrap(), None);
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            snapshot.range(kv, ..).map(|entry| entry.unwrap()).collect();
+        assert_eq!(
+            entries,
+            vec![
+                (byte_vec!("a"), byte_vec!("mandarina")),
+                (byte_vec!("b"), byte_vec!("platan")),
+            ]
+        );
+    };
+
+    check_snapshot(&kv);
+    kv.save_memtable().unwrap();
+    kv.set("a", "maduixa").unwrap();
+    // Waits for the previous memtable and its compaction.
+    kv.save_memtable().unwrap();
+    check_snapshot(&kv);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("maduixa")));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), None);
+
+    // Snapshots only work with the store they were taken from.
+    let (other_kv, other_tmp_dir) = create_kvstore_in_tmp_folder();
+    assert!(matches!(
+        snapshot.get(&other_kv, &byte_vec!("a")),
+        Err(kv_store::Error::InvalidArgument(_))
+    ));
+
+    std::mem::drop(kv);
+    std::mem::drop(other_kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+    fs::remove_dir_all(other_tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_sequence_numbers_survive_reopen() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    let options = || {
+        kv_store::Options::builder()
+            .l0_compaction_trigger(1)
+            .build()
+            .unwrap()
+    };
+
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    kv.set("a", "mandarina").unwrap();
+    kv.set("a", "poma").unwrap();
+    kv.close().unwrap();
+
+    // The new version is compacted with the old ones, and has to be the newest of them even if
+    // the write-ahead log of the old ones is gone.
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    kv.set("a", "pera").unwrap();
+    kv.close().unwrap();
+
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("pera")));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_write_batch() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
+
+    let mut batch = kv_store::WriteBatch::new();
+    batch.set("c", "poma");
+    batch.set("a", "ana");
+    batch.delete("b");
+    assert_eq!(batch.len(), 3);
+    kv.write(batch).unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("ana")));
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), None);
+    assert_eq!(kv.get(&byte_vec!("c")).unwrap(), Some(byte_vec!("poma")));
+
+    // A single invalid write fails the whole batch.
+    let options = kv_store::Options::builder().max_value_size(10).build().unwrap();
+    std::mem::drop(kv);
+    let kv = kv_store::KVStore::open(&tmp_dir, options).unwrap();
+    let mut batch = kv_store::WriteBatch::new();
+    batch.set("d", "pera");
+    batch.set("e", "a value bigger than 10 bytes");
+    let error = kv.write(batch).unwrap_err();
+    assert!(matches!(error, kv_store::Error::InvalidArgument(_)), "{:?}", error);
+    assert_eq!(kv.get(&byte_vec!("d")).unwrap(), None);
+    assert_eq!(kv.get(&byte_vec!("e")).unwrap(), None);
+
+    // Batches are recovered from the write-ahead log.
+    let mut batch = kv_store::WriteBatch::new();
+    batch.set("d", "pera");
+    batch.delete("c");
+    kv.write(batch).unwrap();
+    std::mem::forget(kv);
+
+    let kv = kv_store::KVStore::open(&tmp_dir, kv_store::Options::default()).unwrap();
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("ana")));
+    assert_eq!(kv.get(&byte_vec!("c")).unwrap(), None);
+    assert_eq!(kv.get(&byte_vec!("d")).unwrap(), Some(byte_vec!("pera")));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_compare_and_swap() {
+    let (kv, tmp_dir) = create_kvstore_in_tmp_folder();
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
+    kv.delete(&byte_vec!("b")).unwrap();
+    kv.save_memtable().unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // The current value is read from the sstables.
+    let swap = kv.compare_and_swap("a", Some(&b"poma"[..]), Some(byte_vec!("pera"))).unwrap();
+    assert_eq!(swap, kv_store::Swap::Failed(Some(byte_vec!("mandarina"))));
+    let swap = kv.compare_and_swap("a", Some(&b"mandarina"[..]), Some(byte_vec!("pera"))).unwrap();
+    assert_eq!(swap, kv_store::Swap::Done);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("pera")));
+
+    // And from the memtable.
+    let swap = kv.compare_and_swap("a", Some(&b"pera"[..]), None).unwrap();
+    assert_eq!(swap, kv_store::Swap::Done);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), None);
+    let swap = kv.compare_and_swap("a", Some(&b"pera"[..]), Some(byte_vec!("kiwi"))).unwrap();
+    assert_eq!(swap, kv_store::Swap::Failed(None));
+
+    // Deleted keys are absent.
+    assert_eq!(kv.set_if_absent("b", "kiwi").unwrap(), kv_store::Swap::Done);
+    assert_eq!(
+        kv.set_if_absent("b", "poma").unwrap(),
+        kv_store::Swap::Failed(Some(byte_vec!("kiwi")))
+    );
+    assert_eq!(kv.get(&byte_vec!("b")).unwrap(), Some(byte_vec!("kiwi")));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+fn sstables_size(dir: &str) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sstable"))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+#[test]
+fn test_set_with_ttl() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    let options = || {
+        kv_store::Options::builder()
+            .l0_compaction_trigger(1)
+            .build()
+            .unwrap()
+    };
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+
+    let ttl = Duration::from_millis(500);
+    for i in 0..50 {
+        let value: Vec<u8> = (0..10_000).map(|_| rand::random::<u8>()).collect();
+        kv.set_with_ttl(format!("sessio {:02}", i), value, ttl).unwrap();
+    }
+    kv.set_with_ttl("usuari", "anna", ttl).unwrap();
+    kv.set_with_ttl("idioma", "ca", Duration::from_secs(3600)).unwrap();
+    kv.set("tema", "fosc").unwrap();
+    assert_eq!(kv.get(&byte_vec!("usuari")).unwrap(), Some(byte_vec!("anna")));
+    kv.close().unwrap();
+    let size_before_expiring = sstables_size(&tmp_dir);
+
+    thread::sleep(ttl);
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    assert_eq!(kv.get(&byte_vec!("usuari")).unwrap(), None);
+    assert_eq!(kv.get(&byte_vec!("idioma")).unwrap(), Some(byte_vec!("ca")));
+    let keys: Vec<Vec<u8>> = kv.range(..).map(|entry| entry.unwrap().0).collect();
+    assert_eq!(keys, vec![byte_vec!("idioma"), byte_vec!("tema")]);
+
+    // Expired keys are absent for conditional writes.
+    assert_eq!(kv.set_if_absent("usuari", "pere").unwrap(), kv_store::Swap::Done);
+
+    // The compaction of the new table drops the expired entries from disk.
+    kv.set("sessio 25", "nova").unwrap();
+    kv.close().unwrap();
+    let size = sstables_size(&tmp_dir);
+    assert!(size * 10 < size_before_expiring, "{} -> {}", size_before_expiring, size);
+
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    assert_eq!(kv.get(&byte_vec!("usuari")).unwrap(), Some(byte_vec!("pere")));
+    assert_eq!(kv.get(&byte_vec!("sessio 25")).unwrap(), Some(byte_vec!("nova")));
+    assert_eq!(kv.get(&byte_vec!("sessio 26")).unwrap(), None);
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_shared_between_threads() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    // Small memtables, so they are replaced while other threads read.
+    let options = kv_store::Options::builder()
+        .memtable_size(64 * 1024)
+        .build()
+        .unwrap();
+    let kv = kv_store::KVStore::open(&tmp_dir, options).unwrap();
+
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let kv = kv.clone();
+            thread::spawn(move || {
+                for i in 0..2_000 {
+                    kv.set(format!("{} {:04}", t, i), format!("value {}", i)).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    // Both keys are always written in the same batch, so reads never see them different.
+    let batches = {
+        let kv = kv.clone();
+        thread::spawn(move || {
+            for i in 0..2_000 {
+                let mut batch = kv_store::WriteBatch::new();
+                batch.set("a", format!("{}", i));
+                batch.set("b", format!("{}", i));
+                kv.write(batch).unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let kv = kv.clone();
+            thread::spawn(move || {
+                for _ in 0..2_000 {
+                    let snapshot = kv.snapshot().unwrap();
+                    let a = snapshot.get(&kv, &byte_vec!("a")).unwrap();
+                    let b = snapshot.get(&kv, &byte_vec!("b")).unwrap();
+                    assert_eq!(a, b);
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers.into_iter().chain(readers).chain(iter::once(batches)) {
+        handle.join().unwrap();
+    }
+
+    for t in 0..4 {
+        for i in (0..2_000).step_by(97) {
+            assert_eq!(
+                kv.get(&format!("{} {:04}", t, i).into_bytes()).unwrap(),
+                Some(format!("value {}", i).into_bytes())
+            );
+        }
+    }
+    assert_eq!(kv.range(..).count(), 4 * 2_000 + 2);
+    assert_eq!(kv.get(&byte_vec!("a")).unwrap(), Some(byte_vec!("1999")));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
+
+#[test]
+fn test_pause_compactions() {
+    let tmp_dir = format!("./tmp-{}/", rand::random::<u64>());
+    // Every saved memtable has to be compacted into level 1.
+    let options = || {
+        kv_store::Options::builder()
+            .l0_compaction_trigger(1)
+            .build()
+            .unwrap()
+    };
+
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    kv.pause_compactions();
+    kv.set("fruita", "poma").unwrap();
+    kv.save_memtable().unwrap();
+    kv.set("ciutat", "girona").unwrap();
+    // Closing doesn't wait for paused compactions.
+    kv.close().unwrap();
+    assert_eq!(kv.stats().compactions, 0);
+    std::mem::drop(kv);
+
+    // Compactions are not paused after opening the store again.
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    kv.close().unwrap();
+    assert_eq!(kv.stats().compactions, 1);
+    std::mem::drop(kv);
+
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    kv.pause_compactions();
+    kv.set("fruita", "pera").unwrap();
+    kv.save_memtable().unwrap();
+    kv.resume_compactions();
+    kv.close().unwrap();
+    assert_eq!(kv.stats().compactions, 1);
+    std::mem::drop(kv);
+
+    let kv = kv_store::KVStore::open(&tmp_dir, options()).unwrap();
+    assert_eq!(kv.get(&byte_vec!("fruita")).unwrap(), Some(byte_vec!("pera")));
+    assert_eq!(kv.get(&byte_vec!("ciutat")).unwrap(), Some(byte_vec!("girona")));
+
+    std::mem::drop(kv);
+    fs::remove_dir_all(tmp_dir).expect("Remove tmp folder");
+}
@@ -2,15 +2,15 @@ use std::thread;
 use std::time::Duration;
 
 fn main() {
-    let mut kv : kv_store::KVStore = kv_store::KVStore::new("./tmp-main");
+    let kv : kv_store::KVStore = kv_store::KVStore::open("./tmp-main", kv_store::Options::load("./tmp-main").unwrap()).unwrap();
 
-    kv.set("a", "mandarina");
-    kv.set("b", "platan");
+    kv.set("a", "mandarina").unwrap();
+    kv.set("b", "platan").unwrap();
 
 
-    assert_eq!(kv.get(&String::from("a").into_bytes()).unwrap(), b"mandarina");
-    assert_eq!(kv.get(&String::from("b").into_bytes()).unwrap(), b"platan");
+    assert_eq!(kv.get(&String::from("a").into_bytes()).unwrap().unwrap(), b"mandarina");
+    assert_eq!(kv.get(&String::from("b").into_bytes()).unwrap().unwrap(), b"platan");
 
-    kv.save_memtable();
+    kv.save_memtable().unwrap();
     thread::sleep(Duration::from_millis(1000));
 }